@@ -0,0 +1,53 @@
+#!/bin/bash
+# Read latency with large patch sets: serves N patched files and times reads over a sample.
+# Usage: scripts/bench.sh [count...]   (run scripts/chmod.sh first, fpatch must be SUID root)
+set -e
+
+FPATCH="$(realpath "${FPATCH:-target/release/fpatch}")"
+SAMPLE=1000
+ROUNDS=5
+
+bench() {
+    local count=$1
+    local work
+    work="$(mktemp -d)"
+
+    mkdir -p "$work/targets" "$work/home/.local/share/fpatch"
+
+    for ((i = 0; i < count; i++)); do
+        echo "original $i" > "$work/targets/$i"
+        printf '[[prepend]]\nfile = "%s"\ncontent = "patched\\n"\n\n' "$work/targets/$i"
+    done > "$work/home/.local/share/fpatch/patches.toml"
+
+    HOME="$work/home" "$FPATCH" &
+    local daemon=$!
+
+    # wait until the last target is served through fpatch
+    until head -n1 "$work/targets/$((count - 1))" 2>/dev/null | grep -q patched; do
+        sleep 0.1
+    done
+
+    local files=()
+    for ((i = 0; i < SAMPLE; i++)); do
+        files+=("$work/targets/$(( (i * 7919) % count ))")
+    done
+
+    local begin end
+    begin=$(date +%s%N)
+    for ((r = 0; r < ROUNDS; r++)); do
+        cat "${files[@]}" > /dev/null
+    done
+    end=$(date +%s%N)
+
+    echo "$count patches: $(( (end - begin) / (SAMPLE * ROUNDS) )) ns/read"
+
+    kill -INT $daemon
+    wait $daemon || true
+    rm -rf "$work"
+}
+
+[ $# -eq 0 ] && set -- 10 1000 10000
+
+for count in "$@"; do
+    bench "$count"
+done
@@ -0,0 +1,77 @@
+#!/bin/bash
+# Exec behavior of patched files: scripts keep their shebang and mode, setuid originals and
+# prepends to binaries are refused.
+# Usage: scripts/test-exec.sh   (run as root after scripts/chmod.sh, fpatch must be SUID root)
+set -e
+
+FPATCH="$(realpath "${FPATCH:-target/debug/fpatch}")"
+
+work="$(mktemp -d)"
+mount_point="$work/home/.local/share/fpatch/mp"
+
+mkdir -p "$work/targets" "$work/home/.local/share/fpatch"
+
+printf '#!/bin/sh\necho original\n' > "$work/targets/script.sh"
+chmod 755 "$work/targets/script.sh"
+
+# longer than what the kernel reads of a script to find its interpreter
+printf '#!/bin/sh%300s\necho original\n' "" > "$work/targets/long.sh"
+chmod 755 "$work/targets/long.sh"
+
+cp /bin/true "$work/targets/suid"
+chmod 4755 "$work/targets/suid"
+
+cp /bin/true "$work/targets/binary"
+
+cat > "$work/home/.local/share/fpatch/patches.toml" <<EOF
+[[prepend]]
+file = "$work/targets/script.sh"
+content = "echo injected\n"
+
+[[prepend]]
+file = "$work/targets/long.sh"
+content = "echo injected\n"
+
+[[prepend]]
+file = "$work/targets/suid"
+content = "x"
+
+[[prepend]]
+file = "$work/targets/binary"
+content = "x"
+EOF
+
+HOME="$work/home" "$FPATCH" &
+daemon=$!
+
+cleanup() {
+    kill -INT $daemon
+    wait $daemon || true
+    rm -rf "$work"
+}
+trap cleanup EXIT
+
+until [ -e "$mount_point/.fpatch/status" ] && head -n2 "$work/targets/script.sh" | grep -q injected; do
+    sleep 0.1
+done
+
+failed=0
+
+check() {
+    if [ "$2" == "$3" ]; then
+        echo "ok: $1"
+    else
+        echo "FAILED: $1: expected '$3', got '$2'"
+        failed=1
+    fi
+}
+
+check "shebang kept" "$(head -n1 "$work/targets/script.sh")" "#!/bin/sh"
+check "script runs patched" "$("$work/targets/script.sh" | tr '\n' ' ')" "injected original "
+check "long shebang kept" "$(sed -n 2p "$work/targets/long.sh")" "echo injected"
+check "mode preserved" "$(stat -c %a "$work/targets/script.sh")" "755"
+check "setuid refused" "$(grep -c "$work/targets/suid: refusing" "$mount_point/.fpatch/errors")" "1"
+check "prepend to binary refused" "$(grep -c "$work/targets/binary: refusing" "$mount_point/.fpatch/errors")" "1"
+check "binary runs unpatched" "$("$work/targets/binary" && echo ran)" "ran"
+
+exit $failed
@@ -0,0 +1,58 @@
+#!/bin/bash
+# Cleanup after a SIGKILLed daemon: the guard unmounts the binds, even if it was sent SIGTERM
+# too, and the fuse server exits.
+# Usage: scripts/test-kill.sh   (run as root after scripts/chmod.sh, fpatch must be SUID root)
+set -e
+
+FPATCH="$(realpath "${FPATCH:-target/debug/fpatch}")"
+
+work="$(mktemp -d)"
+mount_point="$work/home/.local/share/fpatch/mp"
+
+mkdir -p "$work/targets" "$work/home/.local/share/fpatch"
+echo original > "$work/targets/file"
+
+cat > "$work/home/.local/share/fpatch/patches.toml" <<EOF2
+[[prepend]]
+file = "$work/targets/file"
+content = "injected\n"
+EOF2
+
+HOME="$work/home" "$FPATCH" &
+daemon=$!
+
+cleanup() {
+    rm -rf "$work"
+}
+trap cleanup EXIT
+
+until [ -e "$mount_point/.fpatch/status" ] && grep -q injected "$work/targets/file"; do
+    sleep 0.1
+done
+
+# like systemd stopping the unit: the guard gets SIGTERM too, then the daemon dies uncleanly
+pkill -TERM -f "$FPATCH guard" || true
+kill -KILL $daemon
+wait $daemon || true
+
+for _ in $(seq 50); do
+    grep -q "$work" /proc/self/mounts || break
+    sleep 0.1
+done
+
+failed=0
+
+check() {
+    if [ "$2" == "$3" ]; then
+        echo "ok: $1"
+    else
+        echo "FAILED: $1: expected '$3', got '$2'"
+        failed=1
+    fi
+}
+
+check "binds removed" "$(grep -c "$work" /proc/self/mounts || true)" "0"
+check "original visible" "$(cat "$work/targets/file")" "original"
+check "fuse server exited" "$(pgrep -fc "$FPATCH mount-fuse" || true)" "0"
+
+exit $failed
@@ -4,6 +4,7 @@ use std::process::Command;
 
 use clap::Parser;
 
+use crate::ctl::Request;
 use crate::extensions::Nop;
 
 #[derive(Parser, Debug)]
@@ -14,13 +15,22 @@ pub struct Args {
 
 #[derive(Parser, Debug)]
 pub enum Operation {
-    MountFuse,
-    PipeBack(PipeBackArgs)
+    MountFuse(MountFuseArgs),
+    PipeBack(PipeBackArgs),
+    Guard(GuardArgs),
+    Ctl(CtlArgs)
 }
 
 pub enum OperationType {
     MountFuse,
-    PipeBack
+    PipeBack,
+    Guard
+}
+
+#[derive(Parser, Debug)]
+pub struct MountFuseArgs {
+    #[clap(long, default_value_t = 0)]
+    pub generation: u64
 }
 
 #[derive(Parser, Debug)]
@@ -29,6 +39,18 @@ pub struct PipeBackArgs {
     pub pid: i32
 }
 
+#[derive(Parser, Debug)]
+pub struct GuardArgs {
+    #[clap(index = 1)]
+    pub pid: i32
+}
+
+#[derive(Parser, Debug)]
+pub struct CtlArgs {
+    #[clap(subcommand)]
+    pub request: Request
+}
+
 pub fn parse_args() -> Args {
     Args::parse()
 }
@@ -40,7 +62,8 @@ pub fn run_op(op: OperationType) -> Command {
 
     match op {
         OperationType::MountFuse => cmd.arg("mount-fuse").nop(),
-        OperationType::PipeBack => cmd.arg("pipe-back").nop()
+        OperationType::PipeBack => cmd.arg("pipe-back").nop(),
+        OperationType::Guard => cmd.arg("guard").nop()
     }
 
     cmd
@@ -1,68 +1,456 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{self, Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-use serde::Deserialize;
+use anyhow::{anyhow, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use toml::Spanned;
 
-use crate::dirs::CONFIG_FILE;
+use crate::dirs::{ACTIVE_CONFIG_FILE, ACTIVE_OVERRIDES_FILE, CONFIG_FILE, OVERRIDES_FILE};
 
 #[derive(Deserialize, Debug)]
 struct PatchModel {
-    file: String,
+    id: Option<String>,
+    file: Spanned<String>,
     content: String,
+    pattern: Option<String>,
+    xattrs: Option<HashMap<String, String>>,
+    when: Option<WhenModel>,
+    write: Option<WriteMode>,
+    cache: Option<CachePolicy>,
     enable: Option<bool>
 }
 
+#[derive(Deserialize, Debug)]
+struct WhenModel {
+    uid: Option<Vec<u32>>,
+    gid: Option<Vec<u32>>,
+    exe: Option<Vec<String>>,
+    cgroup: Option<Vec<String>>
+}
+
+#[derive(Deserialize, Debug)]
+struct FuseModel {
+    attr_ttl: Option<Spanned<f64>>,
+    entry_ttl: Option<Spanned<f64>>,
+    max_read: Option<u32>,
+    default_permissions: Option<bool>,
+    allow: Option<AllowMode>,
+    workers: Option<usize>
+}
+
+#[derive(Deserialize, Debug)]
+struct DaemonModel {
+    backoff: Option<Spanned<f64>>,
+    max_backoff: Option<Spanned<f64>>,
+    max_crashes: Option<usize>,
+    crash_window: Option<Spanned<f64>>
+}
+
 #[derive(Deserialize, Debug)]
 struct PatchConfigsModel {
+    daemon: Option<DaemonModel>,
+    fuse: Option<FuseModel>,
     prepend: Option<Vec<PatchModel>>,
     append: Option<Vec<PatchModel>>,
-    replace: Option<Vec<PatchModel>>
+    replace: Option<Vec<PatchModel>>,
+    substitute: Option<Vec<PatchModel>>
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PatchType {
     Prepend,
     Append,
-    Replace
+    Replace,
+    Substitute
 }
 
-#[derive(Debug)]
+impl PatchType {
+    // computed types render the whole file at once instead of splicing regions
+    pub fn is_computed(&self) -> bool {
+        matches!(self, PatchType::Substitute)
+    }
+}
+
+/// Who besides the mounting user may access the mount.
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllowMode {
+    #[default]
+    Other,
+    Root  // for hosts without `user_allow_other` in /etc/fuse.conf
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuseConfig {
+    pub attr_ttl: Duration,
+    pub entry_ttl: Duration,
+    pub max_read: Option<u32>,
+    pub default_permissions: bool,
+    pub allow: AllowMode,
+    pub workers: usize  // threads serving reads
+}
+
+impl Default for FuseConfig {
+    fn default() -> Self {
+        Self {
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            max_read: None,
+            default_permissions: false,
+            allow: AllowMode::Other,
+            workers: thread::available_parallelism().map_or(4, |n| n.get().max(4))
+        }
+    }
+}
+
+impl FuseConfig {
+    fn from_model(model: FuseModel, source: &Path, text: &str) -> Result<Self> {
+        let default = Self::default();
+
+        Ok(Self {
+            attr_ttl: seconds(source, text, model.attr_ttl, default.attr_ttl)?,
+            entry_ttl: seconds(source, text, model.entry_ttl, default.entry_ttl)?,
+            max_read: model.max_read,
+            default_permissions: model.default_permissions.unwrap_or(default.default_permissions),
+            allow: model.allow.unwrap_or(default.allow),
+            workers: model.workers.unwrap_or(default.workers).max(1)
+        })
+    }
+}
+
+/// How the daemon restarts a crashed fuse server.
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    pub backoff: Duration,  // delay after the first crash, doubled on every further one
+    pub max_backoff: Duration,
+    pub max_crashes: usize,  // crashes within `crash_window` before giving up, 0 never gives up
+    pub crash_window: Duration
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_crashes: 5,
+            crash_window: Duration::from_secs(600)
+        }
+    }
+}
+
+impl DaemonConfig {
+    fn from_model(model: DaemonModel, source: &Path, text: &str) -> Result<Self> {
+        let default = Self::default();
+
+        Ok(Self {
+            backoff: seconds(source, text, model.backoff, default.backoff)?,
+            max_backoff: seconds(source, text, model.max_backoff, default.max_backoff)?,
+            max_crashes: model.max_crashes.unwrap_or(default.max_crashes),
+            crash_window: seconds(source, text, model.crash_window, default.crash_window)?
+        })
+    }
+}
+
+/// How the kernel caches the content of a patched file.
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CachePolicy {
+    #[default]
+    Auto,  // page cache, dropped on every open
+    Keep,  // page cache kept across opens, for static replacements
+    Direct  // no page cache, for constantly changing content
+}
+
+/// How attempts to write a patched file fail.
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WriteMode {
+    #[default]
+    ReadOnly,  // EROFS
+    Deny  // EACCES
+}
+
+/// Which readers see the patched content, everyone else reads the original.
+/// Every selector that is set must match, any value in a selector list matches it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Scope {
+    pub uids: Option<Vec<u32>>,
+    pub gids: Option<Vec<u32>>,
+    pub exes: Option<Vec<PathBuf>>,
+    pub cgroups: Option<Vec<String>>  // cgroup v2 paths, a process in any descendant matches
+}
+
+impl Scope {
+    pub fn is_global(&self) -> bool {
+        self.uids.is_none() && self.gids.is_none() && self.exes.is_none() && self.cgroups.is_none()
+    }
+}
+
+impl From<WhenModel> for Scope {
+    fn from(model: WhenModel) -> Self {
+        // `/proc/<pid>/exe` is always canonical, so compare against canonical paths
+        let exes = model.exe.map(|exes| {
+            exes.into_iter()
+                .map(|exe| fs::canonicalize(&exe).unwrap_or_else(|_| PathBuf::from(exe)))
+                .collect()
+        });
+
+        let cgroups = model.cgroup.map(|cgroups| {
+            cgroups.into_iter()
+                .map(|cgroup| format!("/{}", cgroup.trim_matches('/')))
+                .collect()
+        });
+
+        Self { uids: model.uid, gids: model.gid, exes, cgroups }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct PatchedFile {
+    pub id: String,  // the target path unless set in the config
     pub patch_type: PatchType,
     pub path: PathBuf,
-    pub content: Vec<u8>
+    pub content: Vec<u8>,
+    pub pattern: Option<Vec<u8>>,
+    pub xattrs: HashMap<String, Vec<u8>>,  // added to or overriding the original's
+    pub scope: Scope,
+    pub write_mode: WriteMode,
+    pub cache: CachePolicy
+}
+
+#[derive(Debug)]
+pub struct Configs {
+    pub daemon: DaemonConfig,
+    pub fuse: FuseConfig,
+    pub patches: Vec<PatchedFile>,
+    pub disabled: Vec<PatchedFile>
+}
+
+/// Patches enabled or disabled at runtime, kept apart from the config so that it is never rewritten.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Overrides {
+    #[serde(default)]
+    pub enable: BTreeMap<String, bool>
+}
+
+impl Overrides {
+    pub fn load() -> Result<Self> {
+        Self::parse(&OVERRIDES_FILE, &read_optional(&OVERRIDES_FILE)?)
+    }
+
+    fn parse(source: &Path, overrides_str: &str) -> Result<Self> {
+        toml::from_str(overrides_str).map_err(|e| toml_error(source, overrides_str, e))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(&*OVERRIDES_FILE, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+// lexically absolute, so that every target has exactly one place in the mirrored tree
+fn normalize(file: &str) -> PathBuf {
+    let absolute = path::absolute(file).unwrap_or_else(|_| PathBuf::from(file));
+    let mut normalized = PathBuf::new();
+
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component)
+        }
+    }
+
+    normalized
+}
+
+// `<file>:<line>:<column>: <message>`, pointing at byte `offset` of `text`
+fn error_at(source: &Path, text: &str, offset: usize, message: impl Display) -> Error {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+
+    anyhow!("{}:{}:{}: {}", source.display(), line, column, message)
+}
+
+// `value` seconds, `default` if unset. negative, NaN and overflowing values are errors at the value
+fn seconds(source: &Path, text: &str, value: Option<Spanned<f64>>, default: Duration) -> Result<Duration> {
+    let Some(value) = value else {
+        return Ok(default)
+    };
+
+    Duration::try_from_secs_f64(*value.get_ref())
+        .map_err(|e| error_at(source, text, value.span().start, format!("invalid duration: {e}")))
+}
+
+fn toml_error(source: &Path, text: &str, e: toml::de::Error) -> Error {
+    match e.span() {
+        Some(span) => error_at(source, text, span.start, e.message()),
+        None => anyhow!("{}: {}", source.display(), e.message())
+    }
 }
 
-pub fn parse() -> Vec<PatchedFile> {
-    let configs_str = fs::read_to_string(&*CONFIG_FILE).expect("failed to read config file");
-    let configs: PatchConfigsModel = toml::from_str(&configs_str).expect("failed to parse configs");
+// a missing file reads as empty
+fn read_optional(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(anyhow!(e).context(format!("failed to read {}", path.display())))
+    }
+}
+
+fn parse_str(source: &Path, configs_str: &str, overrides: &Overrides) -> Result<Configs> {
+    let configs: PatchConfigsModel = toml::from_str(configs_str)
+        .map_err(|e| toml_error(source, configs_str, e))?;
 
     let mut patches = vec![];
-    let mut transform = |ty: PatchType, models: Vec<PatchModel>| {
-        models.into_iter().for_each(|model| {
-            if let Some(false) = model.enable {
-                return 
+    let mut disabled = vec![];
+    let mut transform = |ty: PatchType, models: Vec<PatchModel>| -> Result<()> {
+        for model in models {
+            let span = model.file.span();
+            let file = model.file.into_inner();
+
+            if ty.is_computed() && model.pattern.is_none() {
+                return Err(error_at(source, configs_str, span.start, format!("missing pattern for {file}")))
             }
-            
-            patches.push(PatchedFile {
+
+            let path = normalize(&file);
+            let id = model.id.unwrap_or_else(|| path.to_string_lossy().into_owned());
+            let enable = overrides.enable.get(&id).copied().or(model.enable).unwrap_or(true);
+
+            let file = PatchedFile {
+                id,
                 patch_type: ty,
-                path: PathBuf::from(&model.file),
-                content: model.content.into()
-            });
-        });
+                path,
+                content: model.content.into(),
+                pattern: model.pattern.map(Into::into),
+                xattrs: model.xattrs.unwrap_or_default()
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect(),
+                scope: model.when.map(Scope::from).unwrap_or_default(),
+                write_mode: model.write.unwrap_or_default(),
+                cache: model.cache.unwrap_or_default()
+            };
+
+            if enable { patches.push(file) } else { disabled.push(file) }
+        }
+
+        Ok(())
     };
 
     if let Some(models) = configs.prepend {
-        transform(PatchType::Prepend, models);
+        transform(PatchType::Prepend, models)?;
     }
 
     if let Some(models) = configs.append {
-        transform(PatchType::Append, models);
+        transform(PatchType::Append, models)?;
     }
 
     if let Some(models) = configs.replace {
-        transform(PatchType::Replace, models);
+        transform(PatchType::Replace, models)?;
+    }
+
+    if let Some(models) = configs.substitute {
+        transform(PatchType::Substitute, models)?;
     }
 
-    return patches
+    let daemon = match configs.daemon {
+        Some(model) => DaemonConfig::from_model(model, source, configs_str)?,
+        None => DaemonConfig::default()
+    };
+
+    let fuse = match configs.fuse {
+        Some(model) => FuseConfig::from_model(model, source, configs_str)?,
+        None => FuseConfig::default()
+    };
+
+    Ok(Configs { daemon, fuse, patches, disabled })
+}
+
+/// Parses the config and overrides files and, if both are valid, makes them the active ones.
+pub fn update() -> Result<Configs> {
+    let configs_str = fs::read_to_string(&*CONFIG_FILE)
+        .with_context(|| format!("failed to read {}", CONFIG_FILE.display()))?;
+    let overrides_str = read_optional(&OVERRIDES_FILE)?;
+
+    let overrides = Overrides::parse(&OVERRIDES_FILE, &overrides_str)?;
+    let configs = parse_str(&CONFIG_FILE, &configs_str, &overrides)?;
+
+    fs::write(&*ACTIVE_CONFIG_FILE, &configs_str)?;
+    fs::write(&*ACTIVE_OVERRIDES_FILE, &overrides_str)?;
+
+    Ok(configs)
+}
+
+/// The last config accepted by `update`, empty if there never was a valid one.
+pub fn active() -> Result<Configs> {
+    let overrides = Overrides::parse(&ACTIVE_OVERRIDES_FILE, &read_optional(&ACTIVE_OVERRIDES_FILE)?)?;
+    parse_str(&ACTIVE_CONFIG_FILE, &read_optional(&ACTIVE_CONFIG_FILE)?, &overrides)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_count_lines_and_characters() {
+        let err = error_at(Path::new("patches.toml"), "a = 1\nb = \"ä\"x\n", 13, "bad");
+        assert_eq!(err.to_string(), "patches.toml:2:7: bad");
+
+        let err = error_at(Path::new("patches.toml"), "a = 1\n", 0, "bad");
+        assert_eq!(err.to_string(), "patches.toml:1:1: bad");
+    }
+
+    #[test]
+    fn syntax_errors_point_at_their_position() {
+        let text = "[[prepend]]\nfile = \"/etc/hosts\"\ncontent = \n";
+        let err = parse_str(Path::new("patches.toml"), text, &Overrides::default()).unwrap_err();
+        assert!(err.to_string().starts_with("patches.toml:3:11:"), "{err}");
+    }
+
+    #[test]
+    fn missing_patterns_point_at_their_file() {
+        let text = "[[prepend]]\nfile = \"/etc/hosts\"\ncontent = \"\"\n\n[[substitute]]\nfile = \"/etc/hosts\"\ncontent = \"\"\n";
+        let err = parse_str(Path::new("patches.toml"), text, &Overrides::default()).unwrap_err();
+        assert_eq!(err.to_string(), "patches.toml:6:8: missing pattern for /etc/hosts");
+    }
+
+    #[test]
+    fn overrides_errors_point_at_their_line() {
+        let err = Overrides::parse(Path::new("overrides.toml"), "[enable]\n\"/a\" = 1\n").unwrap_err();
+        assert!(err.to_string().starts_with("overrides.toml:2:"), "{err}");
+    }
+
+    #[test]
+    fn missing_overrides_enable_nothing() {
+        assert!(Overrides::parse(Path::new("overrides.toml"), "").unwrap().enable.is_empty());
+    }
+
+    #[test]
+    fn invalid_durations_point_at_their_value() {
+        let overrides = Overrides::default();
+
+        for ttl in ["-1", "nan", "1e300"] {
+            let text = format!("[fuse]\nattr_ttl = {ttl}\n");
+            let err = parse_str(Path::new("patches.toml"), &text, &overrides).unwrap_err();
+            assert!(err.to_string().starts_with("patches.toml:2:12: invalid duration"), "{err}");
+        }
+    }
+
+    #[test]
+    fn invalid_restart_delays_point_at_their_value() {
+        let text = "[daemon]\nbackoff = 1\ncrash_window = -600\n";
+        let err = parse_str(Path::new("patches.toml"), text, &Overrides::default()).unwrap_err();
+        assert!(err.to_string().starts_with("patches.toml:3:16: invalid duration"), "{err}");
+    }
 }
@@ -0,0 +1,164 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream as StdUnixStream;
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use log::{debug, warn};
+use rustix::fs as rfs;
+use rustix::fs::Mode;
+use rustix::process;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as TokioBufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+use crate::dirs::SOCKET_FILE;
+
+// bumped on incompatible changes, requests of any other version are refused
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Commands understood by the daemon, sent one JSON object per line.
+#[derive(Parser, Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    List,
+    Reload,
+    Enable {
+        #[clap(index = 1)]
+        id: String
+    },
+    Disable {
+        #[clap(index = 1)]
+        id: String
+    },
+    Shutdown
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RequestLine {
+    version: u32,
+    #[serde(flatten)]
+    request: Request
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub version: u32,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>
+}
+
+impl Response {
+    pub fn ok(data: Option<Value>) -> Self {
+        Self { version: PROTOCOL_VERSION, ok: true, error: None, data }
+    }
+
+    pub fn error(error: impl Into<String>) -> Self {
+        Self { version: PROTOCOL_VERSION, ok: false, error: Some(error.into()), data: None }
+    }
+}
+
+/// A request waiting for the daemon loop to answer it.
+pub type Command = (Request, oneshot::Sender<Response>);
+
+// a daemon that died leaves the socket file behind, but nothing answers on it
+pub fn is_daemon_running() -> bool {
+    StdUnixStream::connect(&*SOCKET_FILE).is_ok()
+}
+
+pub fn listen() -> Result<UnixListener> {
+    // left behind by a daemon that didn't exit cleanly
+    let _ = fs::remove_file(&*SOCKET_FILE);
+
+    let listener = UnixListener::bind(&*SOCKET_FILE)?;
+
+    // the daemon runs as root, the socket is only for the user who started it
+    rfs::chown(&*SOCKET_FILE, Some(process::getuid()), Some(process::getgid()))?;
+    rfs::chmod(&*SOCKET_FILE, Mode::from_raw_mode(0o600))?;
+
+    debug!("control socket: {:?}", *SOCKET_FILE);
+
+    Ok(listener)
+}
+
+pub async fn serve(listener: UnixListener, commands: mpsc::Sender<Command>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let commands = commands.clone();
+
+        task::spawn(async move {
+            if let Err(e) = handle(stream, commands).await {
+                warn!("control connection: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(stream: UnixStream, commands: mpsc::Sender<Command>) -> Result<()> {
+    let uid = stream.peer_cred()?.uid();
+
+    if uid != 0 && uid != process::getuid().as_raw() {
+        bail!("refused connection from uid {uid}");
+    }
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = TokioBufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<RequestLine>(&line) {
+            Err(e) => Response::error(format!("bad request: {e}")),
+            Ok(line) if line.version != PROTOCOL_VERSION => {
+                Response::error(format!("unsupported protocol version {}", line.version))
+            }
+            Ok(line) => {
+                let (tx, rx) = oneshot::channel();
+
+                commands.send((line.request, tx)).await?;
+                rx.await.unwrap_or_else(|_| Response::error("daemon is shutting down"))
+            }
+        };
+
+        let mut json = serde_json::to_string(&response)?;
+        json.push('\n');
+
+        writer.write_all(json.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+pub fn main(request: Request) -> Result<()> {
+    // nothing here needs root, and the daemon only talks to the user who started it
+    if unsafe { libc::setgid(libc::getgid()) != 0 || libc::setuid(libc::getuid()) != 0 } {
+        bail!("failed to drop privileges");
+    }
+
+    let mut stream = StdUnixStream::connect(&*SOCKET_FILE).context("daemon is not running")?;
+
+    let mut json = serde_json::to_string(&RequestLine { version: PROTOCOL_VERSION, request })?;
+    json.push('\n');
+
+    stream.write_all(json.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    let response: Response = serde_json::from_str(&line).context("bad response from daemon")?;
+
+    if !response.ok {
+        bail!("{}", response.error.unwrap_or_default());
+    }
+
+    if let Some(data) = response.data {
+        println!("{}", serde_json::to_string_pretty(&data)?);
+    }
+
+    Ok(())
+}
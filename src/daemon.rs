@@ -1,148 +1,1037 @@
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
 use std::fs;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Duration;
+use std::future;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use log::{debug, info};
-use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+use anyhow::{anyhow, bail, Result};
+use log::{debug, error, info, warn};
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::ModifyKind;
+use rustix::fs as rfs;
 use rustix::fs::UnmountFlags;
-use rustix::mount;
+use rustix::{mount, process};
+use serde_json::{json, Value};
 use tokio::{select, signal, task, time};
-use tokio::runtime::Handle;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout};
 use tokio::signal::unix;
 use tokio::signal::unix::SignalKind;
-use tokio::sync::{oneshot, mpsc};
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
 
-use crate::{cli, configs};
+use crate::{cli, configs, ctl, fuse};
+use crate::fuse::{Instruction, LoadError, Report};
 use crate::cli::OperationType;
-use crate::configs::PatchedFile;
-use crate::dirs::{CONFIG_FILE, FileNameString, MOUNT_POINT};
-use crate::extensions::{Also, ToTokioCommand};
-use crate::hash::Hash;
+use crate::configs::{Configs, DaemonConfig, FuseConfig, Overrides, PatchedFile};
+use crate::ctl::{Command, Request, Response};
+use crate::dirs::{CONFIG_FILE, MOUNT_POINT, ROOT_DIR, SOCKET_FILE};
+use crate::extensions::ToTokioCommand;
+
+// how long the fuse server may take to mount or reload, rendering every patch on the way
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+// how often failed targets are checked for changes
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// how often binds are verified without a mount table change, which other namespaces don't report
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
 
 pub async fn main() -> Result<()> {
-    crate::mount::cleanup()?;
+    // mounts of a live daemon aren't leftovers
+    if ctl::is_daemon_running() {
+        bail!("fpatch is already running");
+    }
+
+    let leftovers = crate::mount::cleanup()?;
+
+    if leftovers > 0 {
+        warn!("removed {leftovers} mounts left behind by a daemon that didn't exit cleanly");
+    }
+
+    // unmounts for us if we get killed, and is killed itself on a clean exit. in its own
+    // process group, so that Ctrl-C on the terminal doesn't take it down with us
+    let _guard = cli::run_op(OperationType::Guard)
+        .tokio()
+        .arg(format!("{}", process::getpid().as_raw_nonzero()))
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let (tx, mut commands) = mpsc::channel::<Command>(16);
+    task::spawn(ctl::serve(ctl::listen()?, tx.clone()));
+    task::spawn(forward_signals(tx));
+
+    let mut terminate = unix::signal(SignalKind::terminate())?;
+    let mut quit = unix::signal(SignalKind::quit())?;
+
+    let (tx, mut mount_changes) = mpsc::unbounded_channel();
+    crate::mount::watch(move || tx.send(()).is_ok())?;
     
-    let daemon_loop: JoinHandle<Result<()>> = task::spawn(async {
+    let mut daemon_loop: JoinHandle<Result<()>> = task::spawn(async move {
         try {
+            let mut state = State::default();
+
             loop {
-                select! {
-                    _ = run_fuse() => (),
-                    _ = inotify_wait() => {
-                        info!("config file changed, killing fuse server");
+                state.generation += 1;
+
+                let loaded = match configs::update() {
+                    Ok(loaded) => state.accepted(loaded),
+                    Err(e) => {
+                        state.rejected(e);
+                        configs::active()?
                     }
-                }
+                };
+
+                state.policy = loaded.daemon.clone();
+                state.patches = loaded.patches.clone();
+                state.disabled = loaded.disabled;
+
+                let exit = match FuseServer::start(state.generation, loaded.patches).await {
+                    Ok(mut server) => {
+                        let exit = serve(&mut server, loaded.fuse, &mut state, &mut commands, &mut mount_changes).await;
+                        drop(server);
+                        exit
+                    }
+                    Err(e) => {
+                        error!("failed to start fuse server: {e}");
+                        Exit::Crashed
+                    }
+                };
 
-                info!("fuse server exited, restarting in 5 seconds...");
-                time::sleep(Duration::from_secs(5)).await;
+                crate::mount::cleanup()?;
+
+                match exit {
+                    Exit::Restart => (),
+                    Exit::Shutdown => break,
+                    Exit::Crashed => {
+                        let delay = state.backoff.crashed(&state.policy);
+
+                        match delay {
+                            Some(delay) => info!("fuse server crashed, restarting in {delay:?}"),
+                            None => {
+                                let reason = format!(
+                                    "fuse server crashed {} times within {:?}, not restarting until the config changes or a reload is requested",
+                                    state.policy.max_crashes, state.policy.crash_window
+                                );
+
+                                error!("{reason}");
+                                state.stopped = Some(reason);
+                            }
+                        }
+
+                        if !pause(delay, &mut state, &mut commands).await {
+                            break
+                        }
+
+                        state.stopped = None;
+                    }
+                }
             }
         }
     });
     
-    select! {
-        r = daemon_loop => debug!("daemon_loop finished: {r:?}"),
-        r = signal::ctrl_c() => debug!("Ctrl-C: {r:?}")
+    let finished = select! {
+        r = &mut daemon_loop => {
+            debug!("daemon_loop finished: {r:?}");
+            true
+        }
+        r = signal::ctrl_c() => {
+            debug!("Ctrl-C: {r:?}");
+            false
+        }
+        _ = terminate.recv() => {
+            info!("SIGTERM, shutting down");
+            false
+        }
+        _ = quit.recv() => {
+            info!("SIGQUIT, shutting down");
+            false
+        }
+    };
+
+    // the fuse server goes first, so that nothing can bind to it while the mounts are cleaned up
+    if !finished {
+        daemon_loop.abort();
+        daemon_loop.await.unwrap_or_else(|_| Ok(())).unwrap_or_else(|e| error!("daemon loop failed: {e}"));
     }
 
     crate::mount::cleanup()?;
+    fs::remove_file(&*SOCKET_FILE)?;
     
     Ok(())
 }
 
-async fn inotify_wait() -> Result<()> {
-    let handle = Handle::current();
-    let (tx, mut rx) = mpsc::channel(1);
-    
+/// Turns SIGHUP into a reload and SIGUSR2 into a dump of the daemon state to the log.
+async fn forward_signals(commands: mpsc::Sender<Command>) -> Result<()> {
+    let mut hangup = unix::signal(SignalKind::hangup())?;
+    let mut dump = unix::signal(SignalKind::user_defined2())?;
+
+    loop {
+        let requests = select! {
+            _ = hangup.recv() => {
+                info!("SIGHUP, reloading");
+                vec![Request::Reload]
+            }
+            _ = dump.recv() => vec![Request::Status, Request::List]
+        };
+
+        for request in requests {
+            let (tx, rx) = oneshot::channel();
+
+            commands.send((request, tx)).await?;
+            let response = rx.await?;
+
+            if let Some(error) = response.error {
+                warn!("{error}");
+            } else if let Some(data) = response.data {
+                info!("{}", serde_json::to_string_pretty(&data)?);
+            }
+        }
+    }
+}
+
+enum Exit {
+    Restart,  // on purpose, starts over right away
+    Crashed,
+    Shutdown
+}
+
+/// Runs the daemon side of one fuse server until it has to be replaced.
+async fn serve(
+    server: &mut FuseServer,
+    fuse_config: FuseConfig,
+    state: &mut State,
+    commands: &mut mpsc::Receiver<Command>,
+    mount_changes: &mut mpsc::UnboundedReceiver<()>
+) -> Exit {
+    let config_changed = inotify_wait();
+    tokio::pin!(config_changed);
+
+    let mut retry = time::interval(RETRY_INTERVAL);
+    let mut health = time::interval(HEALTH_INTERVAL);
+
+    loop {
+        // requests that reload are answered once the reload is done
+        let (reload, pending) = select! {
+            event = server.next_event() => match event {
+                ServerEvent::Exited(r) => {
+                    warn!("fuse server exited: {r:?}");
+                    return Exit::Crashed
+                }
+                ServerEvent::Replaced(targets) => {
+                    if let Err(e) = server.rebind(targets).await {
+                        warn!("rebind failed: {e}");
+                        return Exit::Crashed
+                    }
+
+                    (false, None)
+                }
+            },
+            _ = &mut config_changed => {
+                config_changed.set(inotify_wait());
+                info!("config file changed, reloading");
+                (true, None)
+            }
+            _ = health.tick() => {
+                if let Err(e) = server.repair(state) {
+                    warn!("health check failed: {e}");
+                    return Exit::Crashed
+                }
+
+                (false, None)
+            }
+            Some(()) = mount_changes.recv() => {
+                while mount_changes.try_recv().is_ok() {}
+
+                if let Err(e) = server.repair(state) {
+                    warn!("health check failed: {e}");
+                    return Exit::Crashed
+                }
+
+                (false, None)
+            }
+            _ = retry.tick(), if !server.failed.is_empty() => {
+                if let Err(e) = server.retry().await {
+                    warn!("retry failed: {e}");
+                    return Exit::Crashed
+                }
+
+                (false, None)
+            }
+            Some((request, reply)) = commands.recv() => {
+                match handle(request, state, Some(server)) {
+                    (response, Next::Continue) => {
+                        let _ = reply.send(response);
+                        (false, None)
+                    }
+                    (response, Next::Reload) => (true, Some((reply, response))),
+                    (response, Next::Shutdown) => {
+                        let _ = reply.send(response);
+                        return Exit::Shutdown
+                    }
+                }
+            }
+        };
+
+        if !reload {
+            continue
+        }
+
+        // an invalid config leaves the current patches in place until it is fixed
+        let loaded = match configs::update() {
+            Ok(loaded) => state.accepted(loaded),
+            Err(e) => {
+                let error = Response::error(format!("{e:#}"));
+                state.rejected(e);
+
+                if let Some((reply, _)) = pending {
+                    let _ = reply.send(error);
+                }
+
+                continue
+            }
+        };
+
+        if let Some((reply, response)) = pending {
+            let _ = reply.send(response);
+        }
+
+        state.policy = loaded.daemon;
+
+        // mount options can only change with a new mount
+        if loaded.fuse != fuse_config {
+            info!("fuse options changed, restarting fuse server");
+            return Exit::Restart
+        }
+
+        state.patches = loaded.patches.clone();
+        state.disabled = loaded.disabled;
+
+        // reloads in place are a generation of their own, like restarts
+        match server.reload(loaded.patches, state.generation + 1).await {
+            Ok(true) => state.generation += 1,
+            Ok(false) => (),
+            Err(e) => {
+                warn!("reload failed: {e}");
+                return Exit::Crashed
+            }
+        }
+    }
+}
+
+/// Waits before restarting a crashed server, or until told to try again if `delay` is none,
+/// answering requests meanwhile. False if the daemon should shut down instead.
+async fn pause(delay: Option<Duration>, state: &mut State, commands: &mut mpsc::Receiver<Command>) -> bool {
+    let sleep = async {
+        match delay {
+            Some(delay) => time::sleep(delay).await,
+            None => future::pending().await
+        }
+    };
+
+    let config_changed = inotify_wait();
+    tokio::pin!(sleep, config_changed);
+
+    // asking for another try explicitly starts the crash count over
+    loop {
+        select! {
+            _ = &mut sleep => return true,
+            _ = &mut config_changed => {
+                info!("config file changed, restarting fuse server");
+                state.backoff.reset();
+                return true
+            }
+            Some((request, reply)) = commands.recv() => {
+                let (response, next) = handle(request, state, None);
+                let _ = reply.send(response);
+
+                match next {
+                    Next::Continue => (),
+                    Next::Reload => {
+                        state.backoff.reset();
+                        return true
+                    }
+                    Next::Shutdown => return false
+                }
+            }
+        }
+    }
+}
+
+/// Recent crashes of the fuse server, which decide how long to wait before the next restart.
+#[derive(Default)]
+struct Backoff {
+    crashes: VecDeque<Instant>
+}
+
+impl Backoff {
+    /// Records a crash. None once the policy allows no more restarts.
+    fn crashed(&mut self, policy: &DaemonConfig) -> Option<Duration> {
+        self.crashed_at(Instant::now(), policy).map(jitter)
+    }
+
+    // the delay before jitter
+    fn crashed_at(&mut self, now: Instant, policy: &DaemonConfig) -> Option<Duration> {
+        self.crashes.retain(|at| now.duration_since(*at) < policy.crash_window);
+        self.crashes.push_back(now);
+
+        if policy.max_crashes > 0 && self.crashes.len() >= policy.max_crashes {
+            return None
+        }
+
+        let exponent = cmp::min(self.crashes.len() - 1, 16) as u32;
+        Some(cmp::min(policy.backoff.saturating_mul(1 << exponent), policy.max_backoff))
+    }
+
+    fn reset(&mut self) {
+        self.crashes.clear();
+    }
+}
+
+// somewhere in [delay / 2, delay), so that restarts don't line up with whatever made the server crash
+fn jitter(delay: Duration) -> Duration {
+    let mut random = [0; 2];
+
+    if getrandom::getrandom(&mut random).is_err() {
+        return delay
+    }
+
+    delay / 2 + delay.mul_f64((u16::from_ne_bytes(random) % 1000) as f64 / 2000.0)
+}
+
+/// What the daemon loop keeps besides the running server, reported through `fpatch ctl`.
+#[derive(Default)]
+struct State {
+    generation: u64,
+    patches: Vec<PatchedFile>,  // enabled in the config, whether or not a server runs them
+    disabled: Vec<PatchedFile>,
+    config_error: Option<String>,  // why the config file was last rejected, cleared once it is valid
+    repairs: u64,  // binds re-applied by health checks
+    policy: DaemonConfig,
+    backoff: Backoff,
+    stopped: Option<String>  // why the server isn't restarted any more
+}
+
+impl State {
+    fn accepted(&mut self, configs: Configs) -> Configs {
+        self.config_error = None;
+        configs
+    }
+
+    fn rejected(&mut self, error: anyhow::Error) {
+        error!("invalid config, keeping the last valid one: {error:#}");
+        self.config_error = Some(format!("{error:#}"));
+    }
+}
+
+enum Next {
+    Continue,
+    Reload,
+    Shutdown
+}
+
+fn handle(request: Request, state: &State, server: Option<&FuseServer>) -> (Response, Next) {
+    let patches = &state.patches;
+    let disabled = &state.disabled;
+    let failed = |target: &PathBuf| server.and_then(|server| server.failed.get(target));
+
+    match request {
+        Request::Status => {
+            let status = json!({
+                "pid": process::getpid().as_raw_nonzero().get(),
+                "generation": state.generation,
+                "active": patches.len(),
+                "disabled": disabled.len(),
+                "running": server.is_some(),
+                "failed": server.map_or(0, |server| server.failed.len()),
+                "repairs": state.repairs,
+                "config_error": state.config_error,
+                "stopped": state.stopped
+            });
+
+            (Response::ok(Some(status)), Next::Continue)
+        }
+        Request::List => {
+            let describe = |file: &PatchedFile, enabled: bool| json!({
+                "id": file.id,
+                "type": format!("{:?}", file.patch_type).to_lowercase(),
+                "file": file.path,
+                "enabled": enabled,
+                "error": failed(&file.path).filter(|_| enabled).map(|failure| &failure.reason)
+            });
+
+            let list = patches.iter().map(|file| describe(file, true))
+                .chain(disabled.iter().map(|file| describe(file, false)))
+                .collect();
+
+            (Response::ok(Some(Value::Array(list))), Next::Continue)
+        }
+        Request::Reload => (Response::ok(None), Next::Reload),
+        Request::Enable { id } => set_enabled(id, true, patches, disabled),
+        Request::Disable { id } => set_enabled(id, false, patches, disabled),
+        Request::Shutdown => (Response::ok(None), Next::Shutdown)
+    }
+}
+
+fn set_enabled(id: String, enable: bool, patches: &[PatchedFile], disabled: &[PatchedFile]) -> (Response, Next) {
+    let enabled = if patches.iter().any(|file| file.id == id) {
+        true
+    } else if disabled.iter().any(|file| file.id == id) {
+        false
+    } else {
+        return (Response::error(format!("no patch with id {id}")), Next::Continue)
+    };
+
+    // a broken file is the user's to fix, not to overwrite
+    let mut overrides = match Overrides::load() {
+        Ok(overrides) => overrides,
+        Err(e) => return (Response::error(format!("{e:#}")), Next::Continue)
+    };
+
+    overrides.enable.insert(id, enable);
+
+    if let Err(e) = overrides.save() {
+        return (Response::error(format!("failed to save overrides: {e}")), Next::Continue)
+    }
+
+    (Response::ok(None), if enabled == enable { Next::Continue } else { Next::Reload })
+}
+
+// the directory is watched rather than the file, so that a config that doesn't exist yet
+// or is replaced by a rename is seen as well
+async fn watch_config() -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
     let mut monitor = RecommendedWatcher::new(
         move |ev| {
-            handle.block_on(async {
-                tx.send(ev).await.unwrap();
-            });
+            let _ = tx.send(ev);
         },
         Config::default()
     )?;
 
-    monitor.watch(&*CONFIG_FILE, RecursiveMode::NonRecursive)?;
-    rx.recv().await;
+    monitor.watch(&ROOT_DIR, RecursiveMode::NonRecursive)?;
+
+    while let Some(ev) = rx.recv().await {
+        match ev? {
+            Event { kind: EventKind::Access(_), .. } => (),
+            ev if ev.paths.contains(&CONFIG_FILE) => return Ok(()),
+            _ => ()
+        }
+    }
 
     Ok(())
 }
 
-async fn run_fuse() -> Result<()> {
-    let files_1 = Arc::new(configs::parse());
-    let files_2 = files_1.clone();
+// resolves when the config file may have changed
+async fn inotify_wait() {
+    if let Err(e) = watch_config().await {
+        // nothing to wait on, check again later
+        debug!("cannot watch config file: {e}");
+        time::sleep(RETRY_INTERVAL).await;
+    }
+}
 
-    let mut fuse = cli::run_op(OperationType::MountFuse)
-        .tokio()
-        .spawn()?;
-    
-    let fuse_pid = fuse.id().unwrap();
+/// What changed between two sets of patches, by target path.
+#[derive(Debug, Default)]
+struct Diff {
+    added: Vec<PathBuf>,
+    removed: Vec<PathBuf>,
+    changed: Vec<PathBuf>,  // same type, different content or options
+    retyped: Vec<PathBuf>,
+    unchanged: usize
+}
 
-    let (tx, rx) = oneshot::channel::<()>();
+impl Diff {
+    fn between(old: &[PatchedFile], new: &[PatchedFile]) -> Self {
+        let by_path = |patches: &'_ [PatchedFile]| -> HashMap<PathBuf, usize> {
+            patches.iter().enumerate().map(|(i, file)| (file.path.clone(), i)).collect()
+        };
 
-    let do_mount: JoinHandle<Result<()>> = task::spawn(async move {
-        try {
-            let mut handler = unix::signal(SignalKind::user_defined1())?;
+        let (old_paths, new_paths) = (by_path(old), by_path(new));
+        let mut diff = Diff::default();
 
-            handler.recv().await.also(|_| debug!("fuse mounted"));
+        for file in new {
+            let Some(i) = old_paths.get(&file.path) else {
+                diff.added.push(file.path.clone());
+                continue
+            };
 
-            cli::run_op(OperationType::PipeBack)
-                .arg(format!("{}", fuse_pid))
-                .status()?;
-            
-            mount_proxies(&files_1)?;
+            let before = &old[*i];
 
-            rx.await?;
+            if before.patch_type != file.patch_type {
+                diff.retyped.push(file.path.clone());
+            } else if before != file {
+                diff.changed.push(file.path.clone());
+            } else {
+                diff.unchanged += 1;
+            }
         }
-    });
 
-    let join: JoinHandle<Result<()>> = task::spawn(async move {
-        try {
-            fuse.wait().await?;
-            tx.send(()).unwrap_or_else(|_| ());
-            restore_all(&files_2)?;
+        for file in old {
+            if !new_paths.contains_key(&file.path) {
+                diff.removed.push(file.path.clone());
+            }
         }
-    });
 
-    select! {
-        r = do_mount => debug!("do_mount finished: {r:?}"),
-        r = join => debug!("run_daemon finished: {r:?}")
+        diff
     }
 
-    crate::mount::cleanup()?;
+    // the server only needs to hear about it if any entry is different
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty() && self.retyped.is_empty()
+    }
+}
 
-    Ok(())
+impl Display for Diff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{} added, {} removed, {} changed, {} retyped, {} unchanged",
+            self.added.len(), self.removed.len(), self.changed.len(), self.retyped.len(), self.unchanged
+        )
+    }
+}
+
+/// Identity of a target file at the time it failed, a change in it is worth another try.
+type TargetState = Option<(u64, u64, (i64, i64), u32)>;  // (st_dev, st_ino, st_mtime, st_mode)
+
+fn target_state(target: &Path) -> TargetState {
+    rfs::stat(target).ok().map(|st| {
+        (st.st_dev as _, st.st_ino as _, (st.st_mtime as _, st.st_mtime_nsec as _), st.st_mode as _)
+    })
+}
+
+// bound targets are served from the fuse mount, anything else is the plain file
+fn is_bound(target: &Path) -> bool {
+    match (rfs::stat(target), rfs::stat(&*MOUNT_POINT)) {
+        (Ok(target), Ok(mount_point)) => target.st_dev == mount_point.st_dev,
+        _ => false
+    }
+}
+
+fn is_mounted() -> bool {
+    match (rfs::stat(&*MOUNT_POINT), rfs::stat(&*ROOT_DIR)) {
+        (Ok(mount_point), Ok(root)) => mount_point.st_dev != root.st_dev,
+        _ => false
+    }
 }
 
-fn mount_proxies(patches: &[PatchedFile]) -> Result<()> {
-    let mut entries: HashMap<String, PathBuf> = HashMap::new();
+/// Watches the parent directories of targets, to notice targets replaced by a rename.
+struct TargetWatcher {
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<PathBuf>
+}
+
+impl TargetWatcher {
+    fn new(patches: &[PatchedFile]) -> Result<Self> {
+        let targets: HashSet<PathBuf> = patches.iter().map(|file| file.path.clone()).collect();
+        let parents: HashSet<PathBuf> = targets.iter().filter_map(|target| target.parent()).map(Path::to_owned).collect();
+
+        let (tx, events) = mpsc::unbounded_channel();
 
-    for entry in fs::read_dir(&*MOUNT_POINT)? {
-        let path = entry.unwrap().path();
+        let mut watcher = RecommendedWatcher::new(
+            move |ev: notify::Result<Event>| {
+                let Ok(ev) = ev else {
+                    return
+                };
 
-        let filename = path.name_string();
-        let hash = filename.split(':').next().unwrap().to_string();
+                if !matches!(ev.kind, EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)) | EventKind::Remove(_)) {
+                    return
+                }
+
+                for path in ev.paths.into_iter().filter(|path| targets.contains(path)) {
+                    let _ = tx.send(path);
+                }
+            },
+            Config::default()
+        )?;
 
-        entries.insert(hash, path);
+        for dir in parents {
+            // failed targets may not have one yet, they are retried anyway
+            if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                debug!("cannot watch {:?}: {}", dir, e);
+            }
+        }
+
+        Ok(Self { _watcher: watcher, events })
     }
 
-    for file in patches {
-        let target = &file.path;
-        let source = &entries[&target.hash()];
+    async fn next(&mut self) -> Vec<PathBuf> {
+        let Some(first) = self.events.recv().await else {
+            return future::pending().await
+        };
+
+        // a replacement comes with several events, handled as one
+        let mut targets = vec![first];
+
+        while let Ok(target) = self.events.try_recv() {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
 
-        crate::mount::bind_mount(source, target)?;
+        targets
     }
+}
 
-    Ok(())
+enum ServerEvent {
+    Exited(Result<ExitStatus>),
+    Replaced(Vec<PathBuf>)
+}
+
+/// A target that couldn't be bound.
+struct Failure {
+    reason: String,
+    seen: TargetState
 }
 
-fn restore_all(patches: &[PatchedFile]) -> Result<()> {
-    for file in patches {
-        mount::unmount(&file.path, UnmountFlags::DETACH)?;
+/// A running `mount-fuse` child and the targets bound to its mirrored files.
+struct FuseServer {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    patches: Vec<PatchedFile>,
+    load_errors: HashMap<PathBuf, String>,  // reported by the server, the reason a target can't be bound
+    failed: HashMap<PathBuf, Failure>,
+    watcher: TargetWatcher
+}
+
+impl FuseServer {
+    async fn start(generation: u64, patches: Vec<PatchedFile>) -> Result<Self> {
+        let mut child = cli::run_op(OperationType::MountFuse)
+            .tokio()
+            .arg(format!("--generation={}", generation))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+
+        let errors = match read_report(&mut stdout).await {
+            Ok(Report::Mounted { errors }) => errors,
+            Ok(Report::Failed { error }) => bail!("fuse server failed to mount: {error}"),
+            Ok(report) => bail!("unexpected report from fuse server: {report:?}"),
+            Err(e) => {
+                // most likely it died, its exit status says more than the closed pipe
+                let status = child.try_wait()?;
+                bail!("no report from fuse server ({}): {e}", status.map_or("running".to_owned(), |s| s.to_string()))
+            }
+        };
+
+        debug!("fuse mounted");
+
+        let status = cli::run_op(OperationType::PipeBack)
+            .arg(format!("{}", child.id().unwrap()))
+            .status()?;
+
+        if !status.success() {
+            bail!("failed to pipe back the fuse mount: {status}");
+        }
+
+        let watcher = TargetWatcher::new(&patches)?;
+        let mut server = Self {
+            child,
+            stdin,
+            stdout,
+            patches,
+            load_errors: HashMap::new(),
+            failed: HashMap::new(),
+            watcher
+        };
+
+        server.set_load_errors(errors);
+
+        let targets: Vec<PathBuf> = server.patches.iter().map(|file| file.path.clone()).collect();
+        targets.iter().for_each(|target| server.bind(target));
+
+        Ok(server)
     }
 
-    Ok(())
+    fn set_load_errors(&mut self, errors: Vec<LoadError>) {
+        self.load_errors = errors.into_iter().map(|error| (error.file, error.reason)).collect();
+    }
+
+    async fn next_event(&mut self) -> ServerEvent {
+        select! {
+            r = self.child.wait() => ServerEvent::Exited(r.map_err(Into::into)),
+            targets = self.watcher.next() => ServerEvent::Replaced(targets)
+        }
+    }
+
+    // a failure only affects its own target, every other patch stays bound
+    fn bind(&mut self, target: &Path) {
+        match bind_proxy(target) {
+            Ok(()) => {
+                self.failed.remove(target);
+            }
+            Err(e) => {
+                let reason = self.load_errors.get(target).cloned().unwrap_or_else(|| e.to_string());
+
+                warn!("failed to bind {:?}: {}", target, reason);
+                self.failed.insert(target.to_owned(), Failure { reason, seen: target_state(target) });
+            }
+        }
+    }
+
+    async fn instruct(&mut self, instruction: Instruction) -> Result<()> {
+        let mut json = serde_json::to_string(&instruction)?;
+        json.push('\n');
+
+        self.stdin.write_all(json.as_bytes()).await?;
+
+        match read_report(&mut self.stdout).await? {
+            Report::Reloaded { errors } => self.set_load_errors(errors),
+            Report::Failed { error } => bail!("fuse server failed to reload: {error}"),
+            report => bail!("unexpected report from fuse server: {report:?}")
+        }
+
+        Ok(())
+    }
+
+    /// Moves the server to `patches`, binding and unbinding only the targets that came or went.
+    // false if nothing changed, the server keeps its generation then
+    async fn reload(&mut self, patches: Vec<PatchedFile>, generation: u64) -> Result<bool> {
+        let diff = Diff::between(&self.patches, &patches);
+
+        if diff.is_empty() {
+            info!("reload: nothing changed");
+            return Ok(false)
+        }
+
+        // unbound before the server drops them, and bound after it serves them
+        for target in &diff.removed {
+            if self.failed.remove(target).is_some() {
+                continue
+            }
+
+            if let Err(e) = mount::unmount(target, UnmountFlags::DETACH) {
+                warn!("failed to unmount {:?}: {}", target, e);
+            }
+        }
+
+        self.instruct(Instruction::Reload { generation }).await?;
+
+        // changed patches may load where their previous version didn't
+        let retried: Vec<PathBuf> = diff.changed.iter()
+            .chain(&diff.retyped)
+            .filter(|target| self.failed.contains_key(*target))
+            .cloned()
+            .collect();
+
+        for target in diff.added.iter().chain(&retried) {
+            self.bind(target);
+        }
+
+        debug!("reload: {diff:?}");
+        info!("reload: {diff}");
+
+        self.watcher = TargetWatcher::new(&patches)?;
+        self.patches = patches;
+
+        Ok(true)
+    }
+
+    /// Picks up targets replaced on disk: the server stats them again, and they are bound again
+    /// if their bind went away with the old file.
+    async fn rebind(&mut self, targets: Vec<PathBuf>) -> Result<()> {
+        self.instruct(Instruction::Refresh { targets: targets.clone() }).await?;
+
+        for target in &targets {
+            if self.failed.contains_key(target) || !is_bound(target) {
+                info!("{:?} was replaced on disk, binding it again", target);
+                self.bind(target);
+            } else {
+                info!("{:?} changed on disk, attributes rebuilt", target);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Binds again every target that is no longer served from the fuse mount.
+    fn repair(&mut self, state: &mut State) -> Result<()> {
+        if !is_mounted() {
+            bail!("the fuse mount is gone");
+        }
+
+        let lost: Vec<PathBuf> = self.patches.iter()
+            .map(|file| &file.path)
+            .filter(|target| !self.failed.contains_key(*target) && !is_bound(target))
+            .cloned()
+            .collect();
+
+        for target in &lost {
+            warn!("{:?} lost its bind mount, repairing", target);
+            self.bind(target);
+
+            if !self.failed.contains_key(target) {
+                state.repairs += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tries failed targets again if their file appeared or changed since they failed.
+    async fn retry(&mut self) -> Result<()> {
+        let changed: Vec<PathBuf> = self.failed.iter()
+            .filter(|(target, failure)| target_state(target) != failure.seen)
+            .map(|(target, _)| target.clone())
+            .collect();
+
+        if changed.is_empty() {
+            return Ok(())
+        }
+
+        info!("retrying {} failed targets", changed.len());
+
+        // every other entry stays as it is
+        self.instruct(Instruction::Refresh { targets: changed.clone() }).await?;
+
+        for target in &changed {
+            self.bind(target);
+        }
+
+        Ok(())
+    }
+}
+
+async fn read_report(stdout: &mut Lines<BufReader<ChildStdout>>) -> Result<Report> {
+    let line = time::timeout(HANDSHAKE_TIMEOUT, stdout.next_line()).await
+        .map_err(|_| anyhow!("timed out after {:?}", HANDSHAKE_TIMEOUT))??;
+
+    let Some(line) = line else {
+        bail!("fuse server closed its output");
+    };
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn bind_proxy(target: &Path) -> Result<()> {
+    crate::mount::bind_mount(&fuse::mirror_path(target), &target.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configs::{CachePolicy, PatchType, Scope, WriteMode};
+
+    fn patch(path: &str, patch_type: PatchType, content: &str) -> PatchedFile {
+        PatchedFile {
+            id: path.to_owned(),
+            patch_type,
+            path: PathBuf::from(path),
+            content: content.into(),
+            pattern: None,
+            xattrs: HashMap::new(),
+            scope: Scope::default(),
+            write_mode: WriteMode::default(),
+            cache: CachePolicy::default()
+        }
+    }
+
+    fn policy(max_crashes: usize) -> DaemonConfig {
+        DaemonConfig {
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            max_crashes,
+            crash_window: Duration::from_secs(60)
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let (mut backoff, now) = (Backoff::default(), Instant::now());
+
+        let delays: Vec<_> = (0..6).map(|_| backoff.crashed_at(now, &policy(0)).unwrap().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn backoff_gives_up_after_max_crashes() {
+        let (mut backoff, now) = (Backoff::default(), Instant::now());
+
+        assert!(backoff.crashed_at(now, &policy(3)).is_some());
+        assert!(backoff.crashed_at(now, &policy(3)).is_some());
+        assert!(backoff.crashed_at(now, &policy(3)).is_none());
+    }
+
+    #[test]
+    fn backoff_forgets_crashes_outside_the_window() {
+        let (mut backoff, now) = (Backoff::default(), Instant::now());
+
+        backoff.crashed_at(now, &policy(3));
+        backoff.crashed_at(now, &policy(3));
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(backoff.crashed_at(later, &policy(3)), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn backoff_starts_over_on_reset() {
+        let (mut backoff, now) = (Backoff::default(), Instant::now());
+
+        backoff.crashed_at(now, &policy(0));
+        backoff.crashed_at(now, &policy(0));
+        backoff.reset();
+
+        assert_eq!(backoff.crashed_at(now, &policy(0)), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn jitter_stays_within_half_the_delay() {
+        let delay = Duration::from_secs(10);
+
+        for _ in 0..100 {
+            let jittered = jitter(delay);
+            assert!(jittered >= delay / 2 && jittered < delay, "{jittered:?}");
+        }
+    }
+
+    #[test]
+    fn diff_classifies_every_target() {
+        let old = [
+            patch("/a", PatchType::Prepend, "x"),
+            patch("/b", PatchType::Prepend, "x"),
+            patch("/c", PatchType::Prepend, "x"),
+            patch("/d", PatchType::Prepend, "x")
+        ];
+        let new = [
+            patch("/a", PatchType::Prepend, "x"),
+            patch("/b", PatchType::Prepend, "y"),
+            patch("/c", PatchType::Append, "x"),
+            patch("/e", PatchType::Prepend, "x")
+        ];
+
+        let diff = Diff::between(&old, &new);
+
+        assert_eq!(diff.added, [PathBuf::from("/e")]);
+        assert_eq!(diff.removed, [PathBuf::from("/d")]);
+        assert_eq!(diff.changed, [PathBuf::from("/b")]);
+        assert_eq!(diff.retyped, [PathBuf::from("/c")]);
+        assert_eq!(diff.unchanged, 1);
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn diff_counts_option_changes() {
+        let old = [patch("/a", PatchType::Prepend, "x")];
+        let mut new = [patch("/a", PatchType::Prepend, "x")];
+        new[0].cache = CachePolicy::Direct;
+
+        assert_eq!(Diff::between(&old, &new).changed, [PathBuf::from("/a")]);
+    }
+
+    #[test]
+    fn diff_of_identical_patches_is_empty() {
+        let patches = [patch("/a", PatchType::Prepend, "x"), patch("/b", PatchType::Replace, "y")];
+        let diff = Diff::between(&patches, &patches);
+
+        assert!(diff.is_empty());
+        assert_eq!(diff.unchanged, 2);
+        assert_eq!(diff.to_string(), "0 added, 0 removed, 0 changed, 0 retyped, 2 unchanged");
+    }
 }
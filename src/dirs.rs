@@ -19,6 +19,25 @@ pub const CONFIG_FILE: Lazy<PathBuf> = Lazy::new(|| {
     ROOT_DIR.join("patches.toml")
 });
 
+// the last valid config file, which is what the fuse server loads
+pub const ACTIVE_CONFIG_FILE: Lazy<PathBuf> = Lazy::new(|| {
+    ROOT_DIR.join("active.toml")
+});
+
+// patches enabled or disabled through `fpatch ctl`, taking precedence over `enable` in the config
+pub const OVERRIDES_FILE: Lazy<PathBuf> = Lazy::new(|| {
+    ROOT_DIR.join("overrides.toml")
+});
+
+// the overrides that went with the active config
+pub const ACTIVE_OVERRIDES_FILE: Lazy<PathBuf> = Lazy::new(|| {
+    ROOT_DIR.join("active-overrides.toml")
+});
+
+pub const SOCKET_FILE: Lazy<PathBuf> = Lazy::new(|| {
+    ROOT_DIR.join("control.sock")
+});
+
 
 pub fn ensure_dir<P : AsRef<Path>>(dir: P) -> Result<()> {
     let dirname = dir.as_ref().to_str().unwrap().to_owned();
@@ -29,13 +48,3 @@ pub fn ensure_dir<P : AsRef<Path>>(dir: P) -> Result<()> {
 
     Ok(())
 }
-
-pub trait FileNameString {
-    fn name_string(&self) -> String;
-}
-
-impl<P : AsRef<Path>> FileNameString for P {
-    fn name_string(&self) -> String {
-        self.as_ref().file_name().unwrap().to_str().unwrap().to_owned()
-    }
-}
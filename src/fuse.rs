@@ -1,25 +1,50 @@
-use std::{cmp, fs};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::os::unix::fs::{FileExt, MetadataExt};
-use std::time::{Duration, UNIX_EPOCH};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{bail, Result};
-use fuser::{FileAttr, Filesystem, FileType, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen, Request};
+use anyhow::{anyhow, bail, Result};
+use fuser::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+use fuser::{BackgroundSession, FileAttr, Filesystem, FileType, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyXattr, Request};
 use libc::*;
-use log::debug;
+use log::{debug, error, info, warn};
 use once_cell::unsync::Lazy;
 use rustix::{fs as rfs, process};
-use rustix::process::Signal;
+use serde::{Deserialize, Serialize};
 
-use crate::dirs;
-use crate::configs::{PatchedFile, PatchType};
-use crate::dirs::{FileNameString, MOUNT_POINT};
-use crate::hash::Hash;
+use crate::{configs, dirs};
+use crate::configs::{AllowMode, CachePolicy, Configs, FuseConfig, PatchedFile, PatchType, WriteMode};
+use crate::dirs::MOUNT_POINT;
+use crate::fuse::cache::{RenderCache, RenderKey};
+use crate::fuse::control::{CONTROL_DIR, ControlFile};
+use crate::fuse::handle::{FileHandle, HandleTable, Pinned};
+use crate::fuse::inode::InodeTable;
+use crate::fuse::pool::WorkerPool;
+use crate::fuse::scope::{Reader, ScopeResolver};
+use crate::fuse::tree::Tree;
 
-const TTL: Duration = Duration::from_secs(1);
+mod access;
+mod cache;
+mod control;
+mod exec;
+mod handle;
+mod inode;
+mod pool;
+mod scope;
+mod tree;
+mod xattr;
 
 pub const ROOT_INO: u64 = 1;  // fuse root
+const ORIGIN_XATTR: &str = "user.fpatch.origin";  // "<dev>:<ino>" of the original file
+const RENDER_CACHE_BUDGET: usize = 64 << 20;
+const PIN_LIMIT: usize = 1 << 20;  // originals up to this size are copied on the first read, larger ones are read in place
 const ROOT_ATTR: Lazy<FileAttr> = Lazy::new(|| FileAttr {
     ino: ROOT_INO,
     size: 0,
@@ -39,250 +64,1037 @@ const ROOT_ATTR: Lazy<FileAttr> = Lazy::new(|| FileAttr {
 });
 
 
-fn generate_attr(file: &PatchedFile) -> FileAttr {
+fn generate_attr(file: &PatchedFile, ino: u64) -> Result<(FileAttr, rfs::Stat), String> {
     let path = &file.path;
-    let src = rfs::stat(path)
-        .unwrap_or_else(|err| panic!("cannot stat {:?}: {}", path, err));
-
-    FileAttr {
-        ino: src.st_ino,
-        size: match file.patch_type {
-            PatchType::Replace => file.content.len() as _,
-            PatchType::Prepend | PatchType::Append => {
-                src.st_size as u64 + file.content.len() as u64
-            }
-        },
+    let src = rfs::stat(path).map_err(|err| format!("cannot stat: {err}"))?;
+
+    if rfs::FileType::from_raw_mode(src.st_mode) != rfs::FileType::RegularFile {
+        return Err("not a regular file".to_owned())
+    }
+
+    let size = match file.patch_type {
+        PatchType::Replace => file.content.len() as _,
+        PatchType::Prepend | PatchType::Append => {
+            src.st_size as u64 + file.content.len() as u64
+        }
+        // only known from a render, see `rendered_size`
+        PatchType::Substitute => src.st_size as _
+    };
+
+    let attr = FileAttr {
+        ino,
+        size,
         blocks: src.st_blocks as _,
         atime: UNIX_EPOCH + Duration::new(src.st_atime as _, src.st_atime_nsec as _),
         mtime: UNIX_EPOCH + Duration::new(src.st_mtime as _, src.st_mtime_nsec as _),
         ctime: UNIX_EPOCH + Duration::new(src.st_ctime as _, src.st_ctime_nsec as _),
         crtime: UNIX_EPOCH,  // mac only
         kind: FileType::RegularFile,  // only regular files are supported
-        perm: (src.st_mode & 0o777) as _,
+        perm: (src.st_mode & 0o7777) as _,
         nlink: src.st_nlink as _,
         uid: src.st_uid,
         gid: src.st_gid,
         rdev: src.st_rdev as _,
         blksize: src.st_blksize as _,
         flags: 0,  // mac only
-    }
+    };
+
+    Ok((attr, src))
 }
 
 
 pub struct FuseEntry {
-    name: String,
     attr: FileAttr,
-    src: Option<PatchedFile>
+    origin: (u64, u64),  // (st_dev, st_ino) of the original file
+    origin_size: u64,
+    src: Option<PatchedFile>,
+    render_lock: Mutex<()>  // held while loading, so each entry reads its source at most once at a time
 }
 
 impl FuseEntry {
-    fn new(name: String, attr: FileAttr, file: Option<PatchedFile>) -> Self {
-        return Self { name, attr, src: file }
-    }
+    fn patched(file: PatchedFile, ino: u64) -> Result<Self, Box<(PatchedFile, String)>> {
+        let (attr, src) = match generate_attr(&file, ino) {
+            Ok(generated) => generated,
+            Err(reason) => return Err(Box::new((file, reason)))
+        };
+
+        if let Err(reason) = exec::check(&file, &src) {
+            return Err(Box::new((file, reason)))
+        }
 
-    fn specials() -> Vec<Self> {
-        vec![
-            Self::new(".".to_owned(), *ROOT_ATTR, None),
-            Self::new("..".to_owned(), *ROOT_ATTR, None)
-        ]
+        Ok(Self {
+            attr,
+            origin: (src.st_dev as _, src.st_ino as _),
+            origin_size: src.st_size as _,
+            src: Some(file),
+            render_lock: Mutex::new(())
+        })
     }
 }
 
-impl From<PatchedFile> for FuseEntry {
-    fn from(file: PatchedFile) -> Self {
-        let filepath = &file.path;
 
-        FuseEntry::new(
-            format!("{}:{}", filepath.hash(), filepath.name_string()),
-            generate_attr(&file),
-            Some(file)
-        )
+/// A patch as the server last loaded it, kept so that a refresh only loads its own targets again.
+type Loaded = Result<Arc<FuseEntry>, Box<(PatchedFile, String)>>;
+
+fn load_patch(file: PatchedFile, inodes: &mut InodeTable) -> Loaded {
+    let ino = inodes.allocate(&file.path);
+
+    FuseEntry::patched(file, ino).map(Arc::new).inspect_err(|failed| {
+        let (file, reason) = &**failed;
+        error!("{:?}: {}", file.path, reason);
+    })
+}
+
+fn loaded_file(loaded: &Loaded) -> &PatchedFile {
+    match loaded {
+        Ok(entry) => entry.src.as_ref().unwrap(),
+        Err(failed) => &failed.0
     }
 }
 
+// stats and checks `targets` again, every other patch keeps its entry
+fn refresh(loaded: &mut [Loaded], targets: &[PathBuf], inodes: &mut InodeTable) {
+    for slot in loaded.iter_mut() {
+        if targets.contains(&loaded_file(slot).path) {
+            let file = loaded_file(slot).clone();
+            *slot = load_patch(file, inodes);
+        }
+    }
+}
+
+/// Everything derived from one set of loaded patches. Built away from the session loop and
+/// swapped in whole.
+struct Snapshot {
+    generation: u64,
+    entries: Vec<Arc<FuseEntry>>,
+    by_ino: HashMap<u64, usize>,
+    errors: Vec<(PathBuf, String)>,
+    controls: HashMap<u64, ControlFile>,
+    tree: Tree
+}
+
+impl Snapshot {
+    // `inodes` is kept across reloads, so that unchanged targets keep their inodes
+    fn build(generation: u64, loaded: &[Loaded], inodes: &mut InodeTable) -> Self {
+        let mut tree = Tree::new(*ROOT_ATTR);
+        let mut entries = vec![];
+        let mut errors = vec![];
+        let mut controls = HashMap::new();
+
+        // linked first, so that patches can't shadow the control files
+        for control in ControlFile::ALL {
+            let path = Path::new(CONTROL_DIR).join(control.name());
+            let ino = inodes.allocate(&path);
+
+            tree.insert(&path, ino, inodes);
+            controls.insert(ino, control);
+        }
+
+        for loaded in loaded {
+            let entry = match loaded {
+                Ok(entry) => entry,
+                Err(failed) => {
+                    let (file, reason) = &**failed;
+                    errors.push((file.path.clone(), reason.clone()));
+                    continue
+                }
+            };
+
+            let path = &entry.src.as_ref().unwrap().path;
+
+            if !tree.insert(path, entry.attr.ino, inodes) {
+                warn!("{:?} conflicts with another patched file, ignored", path);
+                errors.push((path.clone(), "conflicts with another patched file".to_owned()));
+                continue
+            }
+
+            entries.push(entry.clone());
+        }
+
+        let by_ino = entries.iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.attr.ino, i))
+            .collect();
+
+        Self { generation, entries, by_ino, errors, controls, tree }
+    }
+
+    fn load_errors(&self) -> Vec<LoadError> {
+        self.errors.iter()
+            .map(|(file, reason)| LoadError { file: file.clone(), reason: reason.clone() })
+            .collect()
+    }
+}
 
 struct MirrorFileSystem {
-    entries: Vec<FuseEntry>
+    entries: Vec<Arc<FuseEntry>>,
+    by_ino: HashMap<u64, usize>,
+    errors: Vec<(PathBuf, String)>,
+    controls: HashMap<u64, ControlFile>,
+    tree: Tree,
+    handles: HandleTable,
+    cache: Arc<Mutex<RenderCache>>,
+    workers: WorkerPool,
+    scopes: ScopeResolver,
+    config: FuseConfig,
+    daemon_pid: i32,
+    generation: u64,
+    loaded: SystemTime,
+    updates: mpsc::Receiver<Snapshot>  // reloaded patches, installed before the next request
 }
 
 impl MirrorFileSystem {
-    fn new(files: Vec<PatchedFile>) -> Self {
-        let mut entries = FuseEntry::specials();
-        entries.extend(files.into_iter().map(FuseEntry::from));
-        
-        Self { entries }
+    fn new(config: FuseConfig, snapshot: Snapshot, daemon_pid: i32, updates: mpsc::Receiver<Snapshot>) -> Self {
+        let Snapshot { generation, entries, by_ino, errors, controls, tree } = snapshot;
+
+        Self {
+            entries,
+            by_ino,
+            errors,
+            controls,
+            tree,
+            handles: HandleTable::new(),
+            cache: Arc::new(Mutex::new(RenderCache::new(RENDER_CACHE_BUDGET))),
+            workers: WorkerPool::new(config.workers),
+            scopes: ScopeResolver::new(),
+            config,
+            daemon_pid,
+            generation,
+            loaded: SystemTime::now(),
+            updates
+        }
     }
-}
 
-impl Filesystem for MirrorFileSystem {
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if parent != ROOT_INO {
-            reply.error(ENOENT);
-            return;
+    /// Switches to a reloaded snapshot. Open handles keep serving the entries they were opened with.
+    fn install(&mut self, snapshot: Snapshot) {
+        // renders are keyed by the source only, a changed patch makes them wrong
+        for entry in &snapshot.entries {
+            let ino = entry.attr.ino;
+
+            if self.find_by_ino(ino).is_some_and(|old| old.src != entry.src) {
+                self.cache.lock().unwrap().invalidate(ino);
+            }
+        }
+
+        let Snapshot { generation, entries, by_ino, errors, controls, tree } = snapshot;
+
+        self.generation = generation;
+        self.entries = entries;
+        self.by_ino = by_ino;
+        self.errors = errors;
+        self.controls = controls;
+        self.tree = tree;
+        self.loaded = SystemTime::now();
+    }
+
+    fn apply_updates(&mut self) {
+        if let Some(snapshot) = self.updates.try_iter().last() {
+            self.install(snapshot);
+        }
+    }
+
+    fn find_by_ino(&self, ino: u64) -> Option<&FuseEntry> {
+        self.by_ino.get(&ino).map(|i| &*self.entries[*i])
+    }
+
+    fn is_visible(&self, entry: &FuseEntry, reader: &Reader) -> bool {
+        entry.src.as_ref().is_none_or(|file| self.scopes.is_visible(&file.scope, reader))
+    }
+
+    // readers outside the patch scope see the original file
+    fn attr_for(&self, entry: &FuseEntry, reader: &Reader) -> FileAttr {
+        if !self.is_visible(entry, reader) {
+            return FileAttr { size: entry.origin_size, ..entry.attr }
         }
 
-        let name = name.to_str().unwrap();
-        let entry = self.entries.iter().find(|entry| {
-            entry.name == name
+        match &entry.src {
+            Some(file) if file.patch_type.is_computed() => match rfs::stat(&file.path) {
+                Ok(src) => FileAttr { size: self.rendered_size(entry, &src), ..entry.attr },
+                Err(err) => {
+                    debug!("cannot stat {:?}: {err}", file.path);
+                    entry.attr
+                }
+            },
+            _ => entry.attr
+        }
+    }
+
+    // the size of the render of `src`. rendering would hold up the session thread, so until a
+    // worker has rendered this version, the last known size is reported instead
+    fn rendered_size(&self, entry: &FuseEntry, src: &rfs::Stat) -> u64 {
+        let key = render_key(entry.attr.ino, src);
+        let cache = self.cache.lock().unwrap();
+
+        if let Some(size) = cache.size(&key) {
+            return size as _
+        }
+
+        let last = cache.last_size(key.ino).map_or(entry.attr.size, |size| size as _);
+        drop(cache);
+
+        let entry = self.entries[self.by_ino[&key.ino]].clone();
+        let cache = self.cache.clone();
+
+        self.workers.execute(move || {
+            if let Err(err) = prerender(&cache, &entry, &key) {
+                debug!("cannot render {:?}: {err}", entry.src.as_ref().unwrap().path);
+            }
         });
 
-        if let Some(entry) = entry {
-            reply.entry(&TTL, &entry.attr, 0);
-        } else {
-            reply.error(ENOENT)
+        last
+    }
+
+    // the kernel caches attrs per inode, not per reader, so scoped entries can't be cached at all
+    fn ttl_for(&self, ino: u64, ttl: Duration) -> Duration {
+        match self.find_by_ino(ino).and_then(|entry| entry.src.as_ref()) {
+            Some(file) if !file.scope.is_global() => Duration::ZERO,
+            _ => ttl
         }
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        if ino == ROOT_INO {
-            reply.attr(&TTL, &ROOT_ATTR);
-            return;
+    fn node_attr(&self, ino: u64, reader: &Reader) -> Option<FileAttr> {
+        if let Some(dir) = self.tree.dir(ino) {
+            return Some(dir.attr)
         }
 
-        let entry = self.entries.iter().find(|entry| entry.attr.ino == ino);
+        if self.controls.contains_key(&ino) {
+            // generated on open, the size is unknown until then
+            return Some(FileAttr { ino, kind: FileType::RegularFile, perm: 0o444, ..*ROOT_ATTR })
+        }
+
+        self.find_by_ino(ino).map(|entry| self.attr_for(entry, reader))
+    }
+}
+
+impl Filesystem for MirrorFileSystem {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.apply_updates();
+
+        let attr = self.tree.lookup(parent, name)
+            .and_then(|ino| self.node_attr(ino, &Reader::of(req)));
 
-        if let Some(entry) = entry {
-            reply.attr(&TTL, &entry.attr);
+        if let Some(attr) = attr {
+            reply.entry(&self.ttl_for(attr.ino, self.config.entry_ttl), &attr, 0);
         } else {
             reply.error(ENOENT)
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-        let entry = self.entries.iter().find(|entry| entry.attr.ino == ino);
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        self.apply_updates();
 
-        if let Some(FuseEntry { src: Some(_), .. }) = entry {
-            reply.opened(ino, 0);
+        if let Some(attr) = self.node_attr(ino, &Reader::of(req)) {
+            reply.attr(&self.ttl_for(ino, self.config.attr_ttl), &attr);
         } else {
-            reply.error(EINVAL)
+            reply.error(ENOENT)
         }
     }
 
-    fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
-        let entry = self.entries.iter().find(|entry| entry.attr.ino == ino);
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.apply_updates();
+
+        let reader = Reader::of(req);
+
+        if let Some(control) = self.controls.get(&ino).copied() {
+            let attr = self.node_attr(ino, &reader).unwrap();
+
+            if let Err(err) = access::check_open(&attr, &reader, flags, WriteMode::ReadOnly) {
+                reply.error(err);
+                return;
+            }
+
+            let text = control.render(self);
+            let fh = self.handles.insert(FileHandle::snapshot(ino, text.into_bytes()));
+
+            reply.opened(fh, FOPEN_DIRECT_IO);
+            return;
+        }
+
+        let entry = self.find_by_ino(ino);
+
+        let Some(entry @ FuseEntry { src: Some(file), .. }) = entry else {
+            reply.error(EINVAL);
+            return;
+        };
+
+        if let Err(err) = access::check_open(&entry.attr, &reader, flags, file.write_mode) {
+            reply.error(err);
+            return;
+        }
+
+        let patched = self.is_visible(entry, &reader);
+        let executable = exec::is_executable(entry.attr.perm);
+
+        let source = match file.patch_type {
+            PatchType::Replace if patched => Ok(None),
+            _ => File::open(&file.path).and_then(|fp| Ok(Some((render_key(ino, &rfs::fstat(&fp)?), fp))))
+        };
+
+        // scoped entries serve different content per reader, so they can't share the page cache
+        let flags = match file.cache {
+            _ if !file.scope.is_global() => FOPEN_DIRECT_IO,
+            // exec and mmap(PROT_EXEC) need the page cache
+            CachePolicy::Direct if executable => 0,
+            // the source may have been rendered to another size since the last getattr
+            _ if file.patch_type.is_computed() && !executable => FOPEN_DIRECT_IO,
+            CachePolicy::Auto => 0,
+            CachePolicy::Keep => FOPEN_KEEP_CACHE,
+            CachePolicy::Direct => FOPEN_DIRECT_IO
+        };
 
-        if let Some(entry) = entry {
+        match source {
+            Ok(source) => {
+                let (opened, source) = source.unzip();
+                let mut handle = FileHandle::new(ino, patched, source, opened);
+                handle.entry = self.by_ino.get(&ino).map(|i| self.entries[*i].clone());
+
+                let fh = self.handles.insert(handle);
+                reply.opened(fh, flags);
+            }
+            Err(err) => {
+                debug!("failed to open {:?}: {err}", file.path);
+                reply.error(err.raw_os_error().unwrap_or(EIO));
+            }
+        }
+    }
+
+    fn read(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        let Some(handle) = self.handles.get(fh).filter(|handle| handle.ino == ino) else {
+            reply.error(EBADF);
+            return;
+        };
+
+        if let Some(snapshot) = &handle.snapshot {
+            reply.data(slice(snapshot, offset as _, size as _));
+            return;
+        }
+
+        let handle = handle.clone();
+        let entry = handle.entry.clone().unwrap();
+        let cache = self.cache.clone();
+
+        // reads touch the disk and may render whole files, keep them off the session loop
+        self.workers.execute(move || {
             let file = entry.src.as_ref().unwrap();
 
-            if let Ok(data) = do_read(file, offset as _, size as _, entry.attr.size as _) {
-                reply.data(&data);
-            } else {
-                reply.error(EIO);
+            if !handle.patched {
+                match read_original(&handle, offset as _, size as _) {
+                    Ok(data) => reply.data(&data),
+                    Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO))
+                }
+
+                return;
             }
-        } else {
+
+            let read = pin(&cache, &entry, &handle)
+                .and_then(|pinned| read_patched(file, &handle, pinned, offset as _, size as _));
+
+            match read {
+                Ok(data) => reply.data(&data),
+                Err(err) => {
+                    debug!("failed to read {:?}: {err}", file.path);
+                    reply.error(err.raw_os_error().unwrap_or(EIO));
+                }
+            }
+        });
+    }
+
+    fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        self.handles.remove(fh);
+        reply.ok();
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.apply_updates();
+
+        let reader = Reader::of(req);
+
+        let Some(attr) = self.node_attr(ino, &reader) else {
             reply.error(ENOENT);
+            return;
+        };
+
+        let mode = self.find_by_ino(ino)
+            .and_then(|entry| entry.src.as_ref())
+            .map_or(WriteMode::ReadOnly, |file| file.write_mode);
+
+        match access::check(&attr, &reader, mask, mode) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err)
         }
     }
 
-    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        if ino != ROOT_INO {
-            reply.error(ENOENT);
+    fn statfs(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        self.apply_updates();
+
+        // patched files report the filesystem of their original, everything else is virtual
+        let Some(FuseEntry { src: Some(file), .. }) = self.find_by_ino(ino) else {
+            reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
             return;
+        };
+
+        match rfs::statvfs(&file.path) {
+            Ok(st) => reply.statfs(
+                st.f_blocks, st.f_bfree, st.f_bavail, st.f_files, st.f_ffree,
+                st.f_bsize as _, st.f_namemax as _, st.f_frsize as _
+            ),
+            Err(err) => reply.error(err.raw_os_error())
         }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        self.apply_updates();
+
+        let Some(dir) = self.tree.dir(ino) else {
+            reply.error(ENOTDIR);
+            return;
+        };
+
+        let specials = [(OsStr::new("."), ino), (OsStr::new(".."), dir.parent)];
+        let children = dir.children.iter().map(|(name, ino)| (name.as_os_str(), *ino));
 
-        for (i, entry) in self.entries.iter().skip(offset as _).enumerate() {
-            if reply.add(entry.attr.ino, (i + 1) as _, entry.attr.kind, entry.name.clone()) {
+        for (i, (name, child)) in specials.into_iter().chain(children).enumerate().skip(offset as _) {
+            let kind = if self.tree.dir(child).is_some() {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+
+            if reply.add(child, (i + 1) as _, kind, name) {
                 break;
             }
         }
 
         reply.ok();
     }
-}
 
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.apply_updates();
 
-#[derive(Debug)]
-struct FileRegion {
-    s_begin: usize,
-    s_end: usize,
-    d_begin: usize,
-    d_end: usize
-}
+        if self.tree.dir(ino).is_some() || self.controls.contains_key(&ino) {
+            reply.error(ENODATA);
+            return;
+        }
 
-impl FileRegion {
-    fn s_size(&self) -> usize {
-        self.s_end - self.s_begin
+        let Some(entry @ FuseEntry { src: Some(file), .. }) = self.find_by_ino(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if name == ORIGIN_XATTR {
+            let (dev, ino) = entry.origin;
+            xattr::reply(reply, size, format!("{dev}:{ino}").as_bytes());
+            return;
+        }
+
+        // readers outside the patch scope see the attributes of the original only
+        let patched = self.is_visible(entry, &Reader::of(req));
+
+        if let Some(value) = name.to_str().and_then(|name| file.xattrs.get(name)).filter(|_| patched) {
+            xattr::reply(reply, size, value);
+            return;
+        }
+
+        match xattr::original(&file.path, name) {
+            Ok(Some(value)) => xattr::reply(reply, size, &value),
+            Ok(None) => reply.error(ENODATA),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO))
+        }
     }
 
-    fn d_size(&self) -> usize {
-        self.d_end - self.d_begin
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.apply_updates();
+
+        if self.tree.dir(ino).is_some() || self.controls.contains_key(&ino) {
+            xattr::reply(reply, size, &[]);
+            return;
+        }
+
+        let Some(entry @ FuseEntry { src: Some(file), .. }) = self.find_by_ino(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let mut names = match xattr::original_names(&file.path) {
+            Ok(names) => names,
+            Err(err) => {
+                reply.error(err.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+        };
+
+        if self.is_visible(entry, &Reader::of(req)) {
+            for name in file.xattrs.keys() {
+                if !names.iter().any(|other| other == name.as_bytes()) {
+                    names.push(name.as_bytes().to_vec());
+                }
+            }
+        }
+
+        let list = xattr::join_names(names.iter().map(Vec::as_slice));
+        xattr::reply(reply, size, &list);
     }
 }
 
-fn do_read(file: &PatchedFile, begin: usize, size: usize, max_index: usize) -> Result<Vec<u8>> {
-    let end = cmp::min(begin + size, max_index);
-
-    let data = &file.content;
-
-    let s_size = fs::metadata(&file.path)?.size() as usize;
-    let d_size = data.len();
-
-    let region = match file.patch_type {
-        PatchType::Prepend => FileRegion {
-            s_begin: cmp::max(begin, d_size) - d_size,
-            s_end: cmp::max(end, d_size) - d_size,
-            d_begin: cmp::min(begin, d_size),
-            d_end: cmp::min(end, d_size)
-        },
-        PatchType::Append => FileRegion {
-            s_begin: cmp::min(begin, s_size),
-            s_end: cmp::min(end, s_size),
-            d_begin: cmp::max(begin, s_size) - s_size,
-            d_end: cmp::max(end, s_size) - s_size,
-        },
-        PatchType::Replace => FileRegion {
-            s_begin: 0,
-            s_end: 0,
-            d_begin: 0,
-            d_end: d_size,
+// `original` fills its buffer from the original at the given offset
+fn do_read(content: &[u8], pinned: &Pinned, begin: usize, size: usize, original: impl Fn(&mut [u8], usize) -> io::Result<()>) -> io::Result<Vec<u8>> {
+    enum Segment {
+        Source { shift: usize },
+        Content
+    }
+
+    let d_size = content.len();
+    let total = pinned.s_size + d_size;
+
+    let begin = cmp::min(begin, total);
+    let end = cmp::min(begin + size, total);
+
+    // content is injected at `insert_at`: 0 for prepends (or right after a shebang line),
+    // the end of the original for appends. replaces have no source and serve content only
+    let at = pinned.insert_at;
+    let segments = [
+        (0, at, Segment::Source { shift: 0 }),
+        (at, at + d_size, Segment::Content),
+        (at + d_size, total, Segment::Source { shift: d_size })
+    ];
+
+    let mut buffer = vec![0; end - begin];
+
+    for (s_begin, s_end, segment) in segments {
+        let from = cmp::max(begin, s_begin);
+        let to = cmp::min(end, s_end);
+
+        if from >= to {
+            continue
         }
-    };
 
-    let mut src_buffer: Vec<u8> = vec![];
-    let mut data_buffer: Vec<u8> = vec![];
+        let out = &mut buffer[from - begin..to - begin];
 
-    if region.s_size() != 0 {
-        let fp = File::open(&file.path)?;
-        src_buffer.resize(region.s_size(), 0);
-        fp.read_exact_at(&mut src_buffer, region.s_begin as _)?;
+        match segment {
+            Segment::Content => out.copy_from_slice(&content[from - at..to - at]),
+            Segment::Source { shift } => original(out, from - shift)?
+        }
     }
 
-    if region.d_size() != 0 {
-        data_buffer.extend(&data[region.d_begin..region.d_end]);
+    Ok(buffer)
+}
+
+fn slice(data: &[u8], begin: usize, size: usize) -> &[u8] {
+    let begin = cmp::min(begin, data.len());
+    let end = cmp::min(begin + size, data.len());
+
+    &data[begin..end]
+}
+
+
+/// Where the patched version of `target` is served inside the mount.
+pub fn mirror_path(target: &Path) -> PathBuf {
+    tree::components(target).into_iter().fold(MOUNT_POINT.to_path_buf(), |path, name| path.join(name))
+}
+
+// readers outside the scope see the original as it is now, like they would without fpatch
+fn read_original(handle: &FileHandle, begin: usize, size: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0; size];
+    let mut len = 0;
+
+    if let Some(fp) = &handle.source {
+        while len < size {
+            match fp.read_at(&mut buffer[len..], (begin + len) as _)? {
+                0 => break,
+                n => len += n
+            }
+        }
     }
 
+    buffer.truncate(len);
+    Ok(buffer)
+}
+
+fn render(file: &PatchedFile, source: &File, s_size: usize) -> io::Result<Vec<u8>> {
+    let mut original = vec![0; s_size];
+    source.read_exact_at(&mut original, 0)?;
+
     Ok(match file.patch_type {
-        PatchType::Prepend => {
-            data_buffer.extend(src_buffer);
-            data_buffer
+        PatchType::Substitute => {
+            substitute(&original, file.pattern.as_ref().unwrap(), &file.content)
         }
-        PatchType::Append => {
-            src_buffer.extend(data_buffer);
-            src_buffer
+        PatchType::Prepend | PatchType::Append | PatchType::Replace => {
+            unreachable!("{:?} is not a computed patch", file.patch_type)
         }
-        PatchType::Replace => data_buffer
     })
 }
 
+fn substitute(data: &[u8], pattern: &[u8], replacement: &[u8]) -> Vec<u8> {
+    if pattern.is_empty() {
+        return data.to_vec()
+    }
+
+    let mut result = Vec::with_capacity(data.len());
+    let mut index = 0;
+
+    while index < data.len() {
+        if data[index..].starts_with(pattern) {
+            result.extend(replacement);
+            index += pattern.len();
+        } else {
+            result.push(data[index]);
+            index += 1;
+        }
+    }
+
+    result
+}
+
+fn render_key(ino: u64, src: &rfs::Stat) -> RenderKey {
+    RenderKey {
+        ino,
+        s_dev: src.st_dev as _,
+        s_ino: src.st_ino as _,
+        s_mtime: (src.st_mtime as _, src.st_mtime_nsec as _),
+        s_size: src.st_size as _
+    }
+}
+
+// the rendering of the version `source` has now, shared through the cache by every handle on it
+fn load(cache: &Mutex<RenderCache>, entry: &FuseEntry, source: &File) -> io::Result<Arc<Vec<u8>>> {
+    let file = entry.src.as_ref().unwrap();
+    let src = rfs::fstat(source)?;
+    let key = render_key(entry.attr.ino, &src);
+
+    // other entries keep rendering while this one does, the cache itself is only locked briefly
+    let _rendering = entry.render_lock.lock().unwrap();
+
+    if let Some(data) = cache.lock().unwrap().get(&key) {
+        return Ok(data)
+    }
+
+    let data = render(file, source, src.st_size as _)?;
+    Ok(cache.lock().unwrap().put(key, data))
+}
+
+// renders `key` for the getattr that asked for its size. getattrs keep asking until the
+// size is known, only the first one renders
+fn prerender(cache: &Mutex<RenderCache>, entry: &FuseEntry, key: &RenderKey) -> io::Result<()> {
+    let file = entry.src.as_ref().unwrap();
+    let source = File::open(&file.path)?;
+
+    let _rendering = entry.render_lock.lock().unwrap();
+
+    if cache.lock().unwrap().size(key).is_some() {
+        return Ok(())
+    }
+
+    let src = rfs::fstat(&source)?;
+    let data = render(file, &source, src.st_size as _)?;
+
+    cache.lock().unwrap().put(render_key(entry.attr.ino, &src), data);
+    Ok(())
+}
+
+// fixes what `handle` serves on its first read: renders and small originals are kept for
+// the lifetime of the handle, so a rewrite of the original never shows up halfway through
+// a reader. larger originals are read in place, see `read_patched`
+fn pin<'a>(cache: &Mutex<RenderCache>, entry: &FuseEntry, handle: &'a FileHandle) -> io::Result<&'a Pinned> {
+    if let Some(pinned) = handle.pinned.get() {
+        return Ok(pinned)
+    }
+
+    let file = entry.src.as_ref().unwrap();
+
+    let pinned = match &handle.source {
+        // replaces serve their content only
+        None => Pinned { data: Some(Arc::default()), s_size: 0, insert_at: 0 },
+        Some(source) if file.patch_type.is_computed() => {
+            let data = load(cache, entry, source)?;
+            Pinned { s_size: data.len(), data: Some(data), insert_at: 0 }
+        }
+        Some(source) => {
+            let s_size = handle.opened.unwrap().s_size;
+
+            let data = if s_size <= PIN_LIMIT {
+                let mut original = vec![0; s_size];
+                source.read_exact_at(&mut original, 0)?;
+                Some(Arc::new(original))
+            } else {
+                None
+            };
+
+            let insert_at = match (file.patch_type, &data) {
+                (PatchType::Prepend, Some(data)) if exec::is_executable(entry.attr.perm) => exec::shebang_len(data),
+                (PatchType::Prepend, None) if exec::is_executable(entry.attr.perm) => exec::read_shebang_len(source)?,
+                (PatchType::Append, _) => s_size,
+                _ => 0
+            };
+
+            Pinned { data, s_size, insert_at }
+        }
+    };
+
+    // reads racing on the first pin all get the one that won
+    Ok(handle.pinned.get_or_init(|| pinned))
+}
+
+// serves a read from what `pin` fixed
+fn read_patched<'a>(file: &PatchedFile, handle: &FileHandle, pinned: &'a Pinned, begin: usize, size: usize) -> io::Result<Cow<'a, [u8]>> {
+    if file.patch_type.is_computed() {
+        return Ok(Cow::Borrowed(slice(pinned.data.as_ref().unwrap(), begin, size)))
+    }
+
+    if let Some(data) = &pinned.data {
+        return do_read(&file.content, pinned, begin, size, |out, at| {
+            out.copy_from_slice(&data[at..at + out.len()]);
+            Ok(())
+        }).map(Cow::Owned)
+    }
+
+    // too large to copy. the fd already covers a rename over the original, but a rewrite
+    // in place would tear the reader, so that fails instead
+    let source = handle.source.as_ref().unwrap();
+
+    if Some(render_key(handle.ino, &rfs::fstat(source)?)) != handle.opened {
+        return Err(io::Error::from_raw_os_error(ESTALE))
+    }
 
-pub fn mount(files: Vec<PatchedFile>) -> Result<()> {
-    let mfs = MirrorFileSystem::new(files);
-    let options = &[
-        MountOption::RO, MountOption::AllowOther,  
+    do_read(&file.content, pinned, begin, size, |out, at| source.read_exact_at(out, at as _)).map(Cow::Owned)
+}
+
+
+fn mount_options(config: &FuseConfig) -> Vec<MountOption> {
+    let mut options = vec![
+        MountOption::RO,
         MountOption::FSName(env!("CARGO_CRATE_NAME").to_owned())
     ];
+
+    options.push(match config.allow {
+        AllowMode::Other => MountOption::AllowOther,
+        AllowMode::Root => MountOption::AllowRoot
+    });
+
+    if config.default_permissions {
+        options.push(MountOption::DefaultPermissions);
+    }
+
+    if let Some(max_read) = config.max_read {
+        options.push(MountOption::CUSTOM(format!("max_read={max_read}")));
+    }
+
+    options
+}
+
+/// A patch the server couldn't load.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadError {
+    pub file: PathBuf,
+    pub reason: String
+}
+
+/// What the daemon asks of the `mount-fuse` child on stdin, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+pub enum Instruction {
+    Reload { generation: u64 },  // loads the active config again, as `generation` of the patches
+    Refresh { targets: Vec<PathBuf> }  // stats and checks only these targets again
+}
+
+/// What the `mount-fuse` child tells the daemon on the pipe it got as stdout, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum Report {
+    Mounted { errors: Vec<LoadError> },
+    Reloaded { errors: Vec<LoadError> },
+    Failed { error: String }
+}
+
+impl Report {
+    // the daemon may have closed the pipe while shutting down or restarting the server,
+    // there is nobody left to tell then
+    fn send(&self, mut reports: &File) {
+        let sent = serde_json::to_string(self).map_err(io::Error::from).and_then(|mut json| {
+            json.push('\n');
+            reports.write_all(json.as_bytes())
+        });
+
+        if let Err(e) = sent {
+            warn!("failed to send {self:?}: {e}");
+        }
+    }
+}
+
+// the pipe to the daemon, moved off stdout so that nothing printed by the server or its
+// dependencies ends up in the reports. stdout goes to stderr instead
+fn take_stdout() -> io::Result<File> {
+    let reports = rustix::io::fcntl_dupfd_cloexec(io::stdout(), 3)?;
+    rustix::stdio::dup2_stdout(io::stderr())?;
+
+    Ok(File::from(reports))
+}
+
+fn start(generation: u64, reports: File) -> Result<(BackgroundSession, Vec<LoadError>)> {
+    let Configs { fuse: config, patches, .. } = configs::active()?;
+    let options = mount_options(&config);
+
+    let (updates_tx, updates) = mpsc::channel();
+    let mut inodes = InodeTable::new();
+    let mut loaded: Vec<Loaded> = patches.into_iter().map(|file| load_patch(file, &mut inodes)).collect();
+    let snapshot = Snapshot::build(generation, &loaded, &mut inodes);
+    let errors = snapshot.load_errors();
+
+    let daemon_pid = process::getppid().unwrap();
+    let mfs = MirrorFileSystem::new(config, snapshot, daemon_pid.as_raw_nonzero().get(), updates);
     
     dirs::ensure_dir(&*MOUNT_POINT)?;
 
-    let daemon_pid = process::getppid().unwrap();
-    let session = fuser::spawn_mount2(mfs, &*MOUNT_POINT, options)?;
+    let session = fuser::spawn_mount2(mfs, &*MOUNT_POINT, &options)?;
     
     debug!("fuse session: {session:?}");
-    
-    process::kill_process(daemon_pid, Signal::Usr1)?;
+
+    // the daemon sends instructions on stdin and waits for the report on stdout
+    thread::spawn(move || {
+        let mut generation = generation;
+
+        for line in io::stdin().lines() {
+            let Ok(line) = line else {
+                break
+            };
+
+            let result = match serde_json::from_str(&line) {
+                Ok(Instruction::Reload { generation: reloaded }) => configs::active().map(|configs| {
+                    loaded = configs.patches.into_iter().map(|file| load_patch(file, &mut inodes)).collect();
+                    generation = reloaded;
+                }),
+                Ok(Instruction::Refresh { targets }) => {
+                    refresh(&mut loaded, &targets, &mut inodes);
+                    Ok(())
+                }
+                Err(e) => Err(anyhow!("bad request from daemon: {e}"))
+            };
+
+            if let Err(e) = result {
+                Report::Failed { error: format!("{e:#}") }.send(&reports);
+                continue
+            }
+
+            let snapshot = Snapshot::build(generation, &loaded, &mut inodes);
+            let errors = snapshot.load_errors();
+
+            if updates_tx.send(snapshot).is_err() {
+                break
+            }
+
+            Report::Reloaded { errors }.send(&reports);
+        }
+
+        // stdin closes when the daemon dies, whatever killed it, and nothing is left to serve
+        info!("daemon is gone, exiting");
+        std::process::exit(0);
+    });
+
+    Ok((session, errors))
+}
+
+pub fn mount(generation: u64) -> Result<()> {
+    let reports = take_stdout()?;
+
+    let session = match start(generation, reports.try_clone()?) {
+        Ok((session, errors)) => {
+            Report::Mounted { errors }.send(&reports);
+            session
+        }
+        Err(e) => {
+            Report::Failed { error: format!("{e:#}") }.send(&reports);
+            return Err(e)
+        }
+    };
 
     match session.guard.join() {
         Err(e) => bail!("fuse mount crashed: {e:?}"),
         _ => bail!("fuse mount exited unexpectedly")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(content: &[u8], original: &[u8], insert_at: usize, begin: usize, size: usize) -> Vec<u8> {
+        let pinned = Pinned { data: None, s_size: original.len(), insert_at };
+
+        do_read(content, &pinned, begin, size, |out, at| {
+            out.copy_from_slice(&original[at..at + out.len()]);
+            Ok(())
+        }).unwrap()
+    }
+
+    // reads the whole file in chunks of every size, so that every boundary falls inside one
+    fn assert_reads(content: &[u8], original: &[u8], insert_at: usize, expected: &[u8]) {
+        for chunk in 1..=expected.len() + 1 {
+            let read: Vec<u8> = (0..expected.len() + chunk)
+                .step_by(chunk)
+                .flat_map(|begin| read(content, original, insert_at, begin, chunk))
+                .collect();
+
+            assert_eq!(read, expected, "chunks of {chunk}");
+        }
+    }
+
+    #[test]
+    fn reads_prepends() {
+        assert_reads(b"AB", b"0123", 0, b"AB0123");
+        assert_eq!(read(b"AB", b"0123", 0, 1, 2), b"B0");
+    }
+
+    #[test]
+    fn reads_appends() {
+        assert_reads(b"AB", b"0123", 4, b"0123AB");
+        assert_eq!(read(b"AB", b"0123", 4, 3, 2), b"3A");
+    }
+
+    #[test]
+    fn reads_replaces() {
+        assert_reads(b"ABC", b"", 0, b"ABC");
+    }
+
+    #[test]
+    fn reads_prepends_below_a_shebang() {
+        let original = b"#!/bin/sh\necho\n";
+        let at = exec::shebang_len(original);
+
+        assert_reads(b"set -e\n", original, at, b"#!/bin/sh\nset -e\necho\n");
+    }
+
+    #[test]
+    fn reads_nothing_past_the_end() {
+        assert!(read(b"AB", b"0123", 0, 6, 4).is_empty());
+        assert!(read(b"AB", b"0123", 0, 100, 4).is_empty());
+        assert_eq!(read(b"AB", b"0123", 0, 5, 4), b"3");
+    }
+
+    #[test]
+    fn measures_shebang_lines() {
+        assert_eq!(exec::shebang_len(b"#!/bin/sh\necho\n"), 10);
+        assert_eq!(exec::shebang_len(b"#!/bin/sh"), 9);
+        assert_eq!(exec::shebang_len(b"echo\n"), 0);
+        assert_eq!(exec::shebang_len(b"\x7fELF"), 0);
+    }
+
+    #[test]
+    fn substitute_replaces_every_match() {
+        assert_eq!(substitute(b"a-b-c", b"-", b"+"), b"a+b+c");
+        assert_eq!(substitute(b"aaaa", b"aa", b"b"), b"bb");
+        assert_eq!(substitute(b"foo", b"foo", b""), b"");
+    }
+
+    #[test]
+    fn substitute_changes_size() {
+        assert_eq!(substitute(b"x.y", b".", b"::"), b"x::y");
+        assert_eq!(substitute(b"abcabc", b"abc", b"z"), b"zz");
+    }
+
+    #[test]
+    fn substitute_without_matches() {
+        assert_eq!(substitute(b"abc", b"x", b"y"), b"abc");
+        assert_eq!(substitute(b"ab", b"abc", b"y"), b"ab");
+        assert_eq!(substitute(b"", b"a", b"b"), b"");
+        assert_eq!(substitute(b"abc", b"", b"y"), b"abc");
+    }
+}
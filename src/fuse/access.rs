@@ -0,0 +1,126 @@
+use fuser::FileAttr;
+use libc::{c_int, EACCES, EROFS, O_ACCMODE, O_RDONLY, O_TRUNC, R_OK, W_OK, X_OK};
+
+use crate::configs::WriteMode;
+use crate::fuse::scope::Reader;
+
+pub fn write_error(mode: WriteMode) -> c_int {
+    match mode {
+        WriteMode::ReadOnly => EROFS,
+        WriteMode::Deny => EACCES
+    }
+}
+
+/// Checks `mask` (`R_OK`, `W_OK`, `X_OK`) against the permission bits of `attr`, the way the
+/// kernel would for the original file. Patched files can never be written.
+pub fn check(attr: &FileAttr, reader: &Reader, mask: c_int, mode: WriteMode) -> Result<(), c_int> {
+    if mask & W_OK != 0 {
+        return Err(write_error(mode))
+    }
+
+    let perm = attr.perm as c_int;
+
+    if reader.uid == 0 {
+        // root may read anything, but only execute what is executable by someone
+        return if mask & X_OK != 0 && perm & 0o111 == 0 { Err(EACCES) } else { Ok(()) }
+    }
+
+    let granted = if reader.uid == attr.uid {
+        perm >> 6
+    } else if reader.gid == attr.gid {
+        perm >> 3
+    } else {
+        perm
+    } & 0o7;
+
+    if mask & (R_OK | X_OK) & !granted != 0 {
+        return Err(EACCES)
+    }
+
+    Ok(())
+}
+
+/// Checks the flags of an `open` against the original's permissions.
+pub fn check_open(attr: &FileAttr, reader: &Reader, flags: i32, mode: WriteMode) -> Result<(), c_int> {
+    if flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0 {
+        return Err(write_error(mode))
+    }
+
+    check(attr, reader, R_OK, mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use fuser::FileType;
+    use libc::{O_RDWR, O_WRONLY};
+
+    use super::*;
+
+    fn attr(perm: u16) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm,
+            nlink: 1,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            blksize: 0,
+            flags: 0
+        }
+    }
+
+    fn reader(uid: u32, gid: u32) -> Reader {
+        Reader { uid, gid, pid: 1 }
+    }
+
+    #[test]
+    fn owner_group_and_others_get_their_bits() {
+        let attr = attr(0o640);
+
+        assert_eq!(check(&attr, &reader(1000, 100), R_OK, WriteMode::ReadOnly), Ok(()));
+        assert_eq!(check(&attr, &reader(1000, 100), X_OK, WriteMode::ReadOnly), Err(EACCES));
+        assert_eq!(check(&attr, &reader(2000, 100), R_OK, WriteMode::ReadOnly), Ok(()));
+        assert_eq!(check(&attr, &reader(2000, 200), R_OK, WriteMode::ReadOnly), Err(EACCES));
+    }
+
+    #[test]
+    fn owner_bits_win_over_group_bits() {
+        assert_eq!(check(&attr(0o040), &reader(1000, 100), R_OK, WriteMode::ReadOnly), Err(EACCES));
+    }
+
+    #[test]
+    fn root_reads_anything_but_executes_only_executables() {
+        let root = reader(0, 0);
+
+        assert_eq!(check(&attr(0o000), &root, R_OK, WriteMode::ReadOnly), Ok(()));
+        assert_eq!(check(&attr(0o000), &root, X_OK, WriteMode::ReadOnly), Err(EACCES));
+        assert_eq!(check(&attr(0o001), &root, X_OK, WriteMode::ReadOnly), Ok(()));
+    }
+
+    #[test]
+    fn writes_fail_with_the_write_mode() {
+        let root = reader(0, 0);
+
+        assert_eq!(check(&attr(0o666), &root, W_OK, WriteMode::ReadOnly), Err(EROFS));
+        assert_eq!(check(&attr(0o666), &root, W_OK, WriteMode::Deny), Err(EACCES));
+    }
+
+    #[test]
+    fn opens_for_writing_or_truncating_fail() {
+        let (attr, owner) = (attr(0o644), reader(1000, 100));
+
+        assert_eq!(check_open(&attr, &owner, O_RDONLY, WriteMode::ReadOnly), Ok(()));
+        assert_eq!(check_open(&attr, &owner, O_WRONLY, WriteMode::ReadOnly), Err(EROFS));
+        assert_eq!(check_open(&attr, &owner, O_RDWR, WriteMode::Deny), Err(EACCES));
+        assert_eq!(check_open(&attr, &owner, O_RDONLY | O_TRUNC, WriteMode::ReadOnly), Err(EROFS));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Identifies one rendering: the patched entry and the exact version of its source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RenderKey {
+    pub ino: u64,  // fuse inode of the patched entry
+    pub s_dev: u64,
+    pub s_ino: u64,
+    pub s_mtime: (i64, i64),
+    pub s_size: usize
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize
+}
+
+struct CachedRender {
+    data: Arc<Vec<u8>>,
+    tick: u64
+}
+
+/// LRU cache of fully rendered content for computed patch types, bounded by `budget` bytes.
+pub struct RenderCache {
+    budget: usize,
+    renders: HashMap<RenderKey, CachedRender>,
+    lru: BTreeMap<u64, RenderKey>,
+    sizes: HashMap<u64, (RenderKey, usize)>,  // of the last render per entry, kept when the render itself isn't
+    tick: u64,
+    stats: CacheStats
+}
+
+impl RenderCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            renders: HashMap::new(),
+            lru: BTreeMap::new(),
+            sizes: HashMap::new(),
+            tick: 0,
+            stats: CacheStats::default()
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn get(&mut self, key: &RenderKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+
+        let Some(cached) = self.renders.get_mut(key) else {
+            self.stats.misses += 1;
+            return None
+        };
+
+        self.lru.remove(&cached.tick);
+        self.lru.insert(self.tick, *key);
+        cached.tick = self.tick;
+
+        self.stats.hits += 1;
+        Some(cached.data.clone())
+    }
+
+    /// Size of the render of exactly this version, if it was ever rendered since it was the latest.
+    pub fn size(&self, key: &RenderKey) -> Option<usize> {
+        self.sizes.get(&key.ino).filter(|(last, _)| last == key).map(|(_, size)| *size)
+    }
+
+    /// Size of the latest render of `ino`, whatever version of the source it was.
+    pub fn last_size(&self, ino: u64) -> Option<usize> {
+        self.sizes.get(&ino).map(|(_, size)| *size)
+    }
+
+    pub fn put(&mut self, key: RenderKey, data: Vec<u8>) -> Arc<Vec<u8>> {
+        let data = Arc::new(data);
+        self.sizes.insert(key.ino, (key, data.len()));
+
+        // renders larger than the whole budget are served once and never cached
+        if data.len() <= self.budget {
+            self.tick += 1;
+            self.insert(key, data.clone());
+        }
+
+        data
+    }
+
+    /// Drops every render of `ino`, for entries whose patch changed on reload.
+    pub fn invalidate(&mut self, ino: u64) {
+        let outdated: Vec<RenderKey> = self.renders.keys()
+            .filter(|other| other.ino == ino)
+            .copied()
+            .collect();
+
+        outdated.into_iter().for_each(|other| self.remove(&other));
+        self.sizes.remove(&ino);
+    }
+
+    // older versions of the same entry stay until evicted, handles opened on them still read them
+    fn insert(&mut self, key: RenderKey, data: Arc<Vec<u8>>) {
+        self.remove(&key);
+        self.evict(self.budget - data.len());
+
+        self.stats.entries += 1;
+        self.stats.bytes += data.len();
+
+        self.lru.insert(self.tick, key);
+        self.renders.insert(key, CachedRender { data, tick: self.tick });
+    }
+
+    fn remove(&mut self, key: &RenderKey) {
+        if let Some(cached) = self.renders.remove(key) {
+            self.lru.remove(&cached.tick);
+
+            self.stats.entries -= 1;
+            self.stats.bytes -= cached.data.len();
+        }
+    }
+
+    fn evict(&mut self, limit: usize) {
+        while self.stats.bytes > limit {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break
+            };
+
+            self.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(ino: u64, version: i64) -> RenderKey {
+        RenderKey { ino, s_dev: 1, s_ino: ino, s_mtime: (version, 0), s_size: 0 }
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let mut cache = RenderCache::new(100);
+
+        assert!(cache.get(&key(1, 0)).is_none());
+        cache.put(key(1, 0), vec![0; 10]);
+        assert_eq!(cache.get(&key(1, 0)).unwrap().len(), 10);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries, stats.bytes), (1, 1, 1, 10));
+    }
+
+    #[test]
+    fn keeps_versions_of_the_same_entry() {
+        let mut cache = RenderCache::new(100);
+
+        cache.put(key(1, 0), vec![0; 10]);
+        cache.put(key(1, 1), vec![1; 10]);
+
+        assert_eq!(cache.get(&key(1, 0)).unwrap()[0], 0);
+        assert_eq!(cache.get(&key(1, 1)).unwrap()[0], 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = RenderCache::new(30);
+
+        cache.put(key(1, 0), vec![0; 10]);
+        cache.put(key(2, 0), vec![0; 10]);
+        cache.put(key(3, 0), vec![0; 10]);
+        cache.get(&key(1, 0));
+        cache.put(key(4, 0), vec![0; 10]);
+
+        assert!(cache.get(&key(2, 0)).is_none());
+        assert!(cache.get(&key(1, 0)).is_some());
+        assert!(cache.get(&key(3, 0)).is_some());
+        assert!(cache.get(&key(4, 0)).is_some());
+        assert_eq!(cache.stats().bytes, 30);
+    }
+
+    #[test]
+    fn serves_but_skips_renders_over_budget() {
+        let mut cache = RenderCache::new(10);
+
+        assert_eq!(cache.put(key(1, 0), vec![0; 20]).len(), 20);
+        assert!(cache.get(&key(1, 0)).is_none());
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn remembers_sizes_of_renders_over_budget() {
+        let mut cache = RenderCache::new(10);
+
+        cache.put(key(1, 0), vec![0; 20]);
+        assert_eq!(cache.size(&key(1, 0)), Some(20));
+
+        cache.put(key(1, 1), vec![0; 30]);
+        assert_eq!(cache.size(&key(1, 0)), None);
+        assert_eq!(cache.size(&key(1, 1)), Some(30));
+        assert_eq!(cache.last_size(1), Some(30));
+    }
+
+    #[test]
+    fn replaces_a_key_put_twice() {
+        let mut cache = RenderCache::new(100);
+
+        cache.put(key(1, 0), vec![0; 10]);
+        cache.put(key(1, 0), vec![0; 20]);
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (1, 20));
+    }
+
+    #[test]
+    fn invalidates_every_version_of_an_entry() {
+        let mut cache = RenderCache::new(100);
+
+        cache.put(key(1, 0), vec![0; 10]);
+        cache.put(key(1, 1), vec![0; 10]);
+        cache.put(key(2, 0), vec![0; 10]);
+        cache.invalidate(1);
+
+        assert!(cache.get(&key(1, 0)).is_none());
+        assert!(cache.get(&key(1, 1)).is_none());
+        assert!(cache.get(&key(2, 0)).is_some());
+        assert_eq!(cache.stats().bytes, 10);
+        assert_eq!(cache.size(&key(1, 1)), None);
+        assert_eq!(cache.last_size(1), None);
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+use super::MirrorFileSystem;
+
+pub const CONTROL_DIR: &str = "/.fpatch";
+
+/// Read-only files describing the state of fpatch, generated on every open.
+#[derive(Debug, Copy, Clone)]
+pub enum ControlFile {
+    Status,
+    Patches,
+    Errors
+}
+
+impl ControlFile {
+    pub const ALL: [ControlFile; 3] = [ControlFile::Status, ControlFile::Patches, ControlFile::Errors];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlFile::Status => "status",
+            ControlFile::Patches => "patches",
+            ControlFile::Errors => "errors"
+        }
+    }
+
+    pub fn render(&self, fs: &MirrorFileSystem) -> String {
+        let mut text = String::new();
+
+        match self {
+            ControlFile::Status => {
+                let loaded = fs.loaded.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let cache = fs.cache.lock().unwrap().stats();
+
+                writeln!(text, "pid: {}", fs.daemon_pid).unwrap();
+                writeln!(text, "generation: {}", fs.generation).unwrap();
+                writeln!(text, "loaded: {}", loaded.as_secs()).unwrap();
+                writeln!(text, "patches: {} active, {} failed", fs.entries.len(), fs.errors.len()).unwrap();
+                writeln!(
+                    text, "cache: hits={} misses={} entries={} bytes={}",
+                    cache.hits, cache.misses, cache.entries, cache.bytes
+                ).unwrap();
+            }
+            ControlFile::Patches => {
+                for file in fs.entries.iter().filter_map(|entry| entry.src.as_ref()) {
+                    let patch_type = format!("{:?}", file.patch_type).to_lowercase();
+                    writeln!(text, "{}\t{}", patch_type, file.path.display()).unwrap();
+                }
+            }
+            ControlFile::Errors => {
+                for (path, reason) in &fs.errors {
+                    writeln!(text, "{}: {}", path.display(), reason).unwrap();
+                }
+            }
+        }
+
+        text
+    }
+}
@@ -0,0 +1,94 @@
+//! Exec behavior of patched files.
+//!
+//! - the patched file keeps the mode bits and owner of its original, so scripts and
+//!   binaries stay executable by the same users
+//! - prepends to a script go below its `#!` line, however long it is, so the interpreter
+//!   doesn't change. prepends and substitutions on executables without one, like ELF
+//!   binaries, are refused: they would move or rewrite the bytes the loader reads
+//! - executables are always served through the page cache, even with `cache = "direct"`,
+//!   since exec and `mmap(PROT_EXEC)` need it. for the same reason, scoped patches are
+//!   refused on executables: the page cache is shared by every reader
+//! - setuid/setgid originals and originals with file capabilities are refused. the mount
+//!   may carry nosuid, and a patched privileged binary is a privilege escalation anyway.
+//!   originals on filesystems without xattrs have no capabilities
+//!
+//! `scripts/test-exec.sh` checks these against a live mount.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use libc::{ENOTSUP, S_ISGID, S_ISUID};
+use rustix::fs as rfs;
+
+use crate::configs::{PatchedFile, PatchType};
+use crate::fuse::xattr;
+
+pub fn is_executable(perm: u16) -> bool {
+    perm & 0o111 != 0
+}
+
+/// Refuses patches whose original can't keep its exec semantics behind fpatch.
+pub fn check(file: &PatchedFile, src: &rfs::Stat) -> Result<(), String> {
+    let mode = src.st_mode as libc::mode_t;
+
+    if mode & (S_ISUID | S_ISGID) != 0 {
+        return Err("refusing to patch a setuid/setgid file".to_owned())
+    }
+
+    match xattr::original(&file.path, OsStr::new("security.capability")) {
+        Ok(None) => (),
+        Ok(Some(_)) => return Err("refusing to patch a file with capabilities".to_owned()),
+        // no xattr support, no file capabilities. EOPNOTSUPP is the same errno on linux
+        Err(err) if matches!(err.raw_os_error(), Some(ENOTSUP)) => (),
+        Err(err) => return Err(format!("cannot read capabilities: {err}"))
+    }
+
+    if !is_executable(mode as u16) {
+        return Ok(())
+    }
+
+    // executables are mapped from the page cache, which all readers share
+    if !file.scope.is_global() {
+        return Err("scoped patches can't be applied to executables".to_owned())
+    }
+
+    if matches!(file.patch_type, PatchType::Prepend | PatchType::Substitute) {
+        let mut magic = [0; 2];
+
+        let read = File::open(&file.path).and_then(|source| source.read_at(&mut magic, 0));
+        let len = read.map_err(|err| format!("cannot read: {err}"))?;
+
+        if !magic[..len].starts_with(b"#!") {
+            return Err("refusing to prepend to or substitute in an executable without a #! line".to_owned())
+        }
+    }
+
+    Ok(())
+}
+
+/// Length of the `#!` line of a script including its newline, 0 if there is none, so
+/// that prepended content goes below the interpreter line instead of breaking it.
+pub fn shebang_len(data: &[u8]) -> usize {
+    if !data.starts_with(b"#!") {
+        return 0
+    }
+
+    data.iter().position(|c| *c == b'\n').map_or(data.len(), |i| i + 1)
+}
+
+/// `shebang_len` of an original too large to read whole, read up to the end of its first line.
+pub fn read_shebang_len(source: &File) -> io::Result<usize> {
+    let mut head = vec![];
+    let mut chunk = [0; 4096];
+
+    loop {
+        let n = source.read_at(&mut chunk, head.len() as _)?;
+        head.extend(&chunk[..n]);
+
+        if n == 0 || chunk[..n].contains(&b'\n') || (head.len() >= 2 && !head.starts_with(b"#!")) {
+            return Ok(shebang_len(&head))
+        }
+    }
+}
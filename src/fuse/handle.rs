@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::{Arc, OnceLock};
+
+use crate::fuse::FuseEntry;
+use crate::fuse::cache::RenderKey;
+
+/// What a patched handle serves its source from, fixed on its first read.
+pub struct Pinned {
+    pub data: Option<Arc<Vec<u8>>>,  // a render, or a copy of a small original. None reads the original in place
+    pub s_size: usize,  // of `data`, or of the original as it was at `open`
+    pub insert_at: usize  // where the patch content goes, see `do_read`
+}
+
+/// An opened patched file: the source is opened once at `open`, so a rename over the
+/// original doesn't change what the handle reads.
+pub struct FileHandle {
+    pub ino: u64,
+    pub entry: Option<Arc<FuseEntry>>,  // as it was at `open`, reloads don't change what a handle serves
+    pub patched: bool,  // false if the reader is outside the patch scope
+    pub source: Option<File>,
+    pub opened: Option<RenderKey>,  // version of `source` at `open`, to tell a rewrite in place
+    pub pinned: OnceLock<Pinned>,
+    pub snapshot: Option<Vec<u8>>  // generated content, fixed for the lifetime of the handle
+}
+
+impl FileHandle {
+    pub fn new(ino: u64, patched: bool, source: Option<File>, opened: Option<RenderKey>) -> Self {
+        Self { ino, entry: None, patched, source, opened, pinned: OnceLock::new(), snapshot: None }
+    }
+
+    pub fn snapshot(ino: u64, data: Vec<u8>) -> Self {
+        Self {
+            ino,
+            entry: None,
+            patched: true,
+            source: None,
+            opened: None,
+            pinned: OnceLock::new(),
+            snapshot: Some(data)
+        }
+    }
+}
+
+pub struct HandleTable {
+    handles: HashMap<u64, Arc<FileHandle>>,  // shared with the workers serving reads
+    next_fh: u64
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self { handles: HashMap::new(), next_fh: 1 }
+    }
+
+    pub fn insert(&mut self, handle: FileHandle) -> u64 {
+        let fh = self.next_fh;
+
+        self.next_fh += 1;
+        self.handles.insert(fh, Arc::new(handle));
+
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&Arc<FileHandle>> {
+        self.handles.get(&fh)
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<Arc<FileHandle>> {
+        self.handles.remove(&fh)
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::ROOT_INO;
+
+// inodes below this are never handed out for patched files, so that the root and any
+// other fixed nodes can never collide with them
+pub const RESERVED_INO: u64 = 0x1000;
+
+/// Maps target paths to fpatch-owned inode numbers.
+///
+/// The inode of a path is derived from the md5 of the path, so the same target keeps
+/// the same inode across reloads. Collisions are resolved by probing the next free number.
+pub struct InodeTable {
+    inodes: HashMap<PathBuf, u64>,
+    used: HashSet<u64>
+}
+
+impl InodeTable {
+    pub fn new() -> Self {
+        let used = HashSet::from([ROOT_INO]);
+        Self { inodes: HashMap::new(), used }
+    }
+
+    pub fn allocate(&mut self, path: &Path) -> u64 {
+        if let Some(ino) = self.inodes.get(path) {
+            return *ino
+        }
+
+        let ino = self.free_from(seed(path));
+
+        self.used.insert(ino);
+        self.inodes.insert(path.to_owned(), ino);
+
+        ino
+    }
+
+    // the first inode from `ino` on that is neither reserved nor taken
+    fn free_from(&self, mut ino: u64) -> u64 {
+        while ino < RESERVED_INO || self.used.contains(&ino) {
+            ino = ino.wrapping_add(1);
+        }
+
+        ino
+    }
+}
+
+fn seed(path: &Path) -> u64 {
+    let digest = md5::compute(path.as_os_str().as_encoded_bytes());
+    u64::from_le_bytes(digest.0[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_inode_of_a_path() {
+        let mut inodes = InodeTable::new();
+        let ino = inodes.allocate(Path::new("/etc/hosts"));
+
+        assert_eq!(inodes.allocate(Path::new("/etc/hosts")), ino);
+        assert_eq!(InodeTable::new().allocate(Path::new("/etc/hosts")), ino);
+        assert_ne!(inodes.allocate(Path::new("/etc/passwd")), ino);
+    }
+
+    #[test]
+    fn probes_past_taken_inodes() {
+        let mut inodes = InodeTable::new();
+        let path = Path::new("/etc/hosts");
+
+        // as if another path had hashed to the same inode first
+        inodes.used.insert(seed(path));
+        assert_eq!(inodes.allocate(path), seed(path).wrapping_add(1));
+    }
+
+    #[test]
+    fn never_hands_out_reserved_inodes() {
+        let mut inodes = InodeTable::new();
+
+        assert_eq!(inodes.free_from(0), RESERVED_INO);
+        assert_eq!(inodes.free_from(ROOT_INO), RESERVED_INO);
+
+        inodes.used.insert(u64::MAX);
+        assert_eq!(inodes.free_from(u64::MAX), RESERVED_INO);
+    }
+}
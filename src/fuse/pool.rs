@@ -0,0 +1,43 @@
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use log::debug;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed set of threads serving slow requests, so that one large read doesn't hold up
+/// the fuse session loop and every other patched file with it.
+pub struct WorkerPool {
+    sender: mpsc::Sender<Job>
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0 .. workers {
+            let receiver = receiver.clone();
+
+            thread::Builder::new()
+                .name(format!("fuse-worker-{i}"))
+                .spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break  // pool dropped
+                    }
+                })
+                .expect("failed to spawn fuse worker");
+        }
+
+        debug!("fuse worker pool: {workers} threads");
+
+        Self { sender }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.sender.send(Box::new(job)).expect("fuse workers are gone");
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use fuser::Request;
+
+use crate::configs::Scope;
+
+// forget everything once this many processes are remembered, dead pids are never pruned otherwise
+const PROCESS_CACHE_LIMIT: usize = 4096;
+
+/// The process a request was made on behalf of.
+#[derive(Debug, Copy, Clone)]
+pub struct Reader {
+    pub uid: u32,
+    pub gid: u32,  // primary group only, fuse doesn't forward supplementary groups
+    pub pid: u32
+}
+
+impl Reader {
+    pub fn of(req: &Request<'_>) -> Self {
+        Self { uid: req.uid(), gid: req.gid(), pid: req.pid() }
+    }
+}
+
+/// Identifies one incarnation of a pid by its start time, which a reused pid never shares.
+/// The command name isn't part of it, the process can set that itself.
+#[derive(Debug, PartialEq, Eq)]
+struct Incarnation {
+    starttime: u64
+}
+
+impl Incarnation {
+    fn of(pid: u32) -> Option<Self> {
+        let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+
+        // comm may contain spaces and parentheses, it spans to the last ')'
+        let comm_end = stat.rfind(')')?;
+
+        // fields after comm start at `state` (3), `starttime` is field 22
+        let starttime = stat[comm_end + 1..].split_ascii_whitespace().nth(19)?.parse().ok()?;
+
+        Some(Self { starttime })
+    }
+}
+
+struct ProcessInfo {
+    incarnation: Incarnation,
+    exe: Option<PathBuf>
+}
+
+/// Decides whether a reader is inside the scope of a patch.
+pub struct ScopeResolver {
+    processes: Mutex<HashMap<u32, ProcessInfo>>
+}
+
+impl ScopeResolver {
+    pub fn new() -> Self {
+        Self { processes: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn is_visible(&self, scope: &Scope, reader: &Reader) -> bool {
+        fn selects<T: PartialEq>(selector: &Option<Vec<T>>, value: &T) -> bool {
+            selector.as_ref().is_none_or(|values| values.contains(value))
+        }
+
+        if !selects(&scope.uids, &reader.uid) || !selects(&scope.gids, &reader.gid) {
+            return false
+        }
+
+        if let Some(exes) = &scope.exes {
+            match self.exe(reader.pid) {
+                Some(exe) if exes.contains(&exe) => (),
+                _ => return false
+            }
+        }
+
+        if let Some(cgroups) = &scope.cgroups {
+            let Some(cgroup) = cgroup_of(reader.pid) else {
+                return false
+            };
+
+            let selected = cgroups.iter().any(|selector| {
+                selector == "/" || cgroup == *selector || cgroup.starts_with(&format!("{selector}/"))
+            });
+
+            if !selected {
+                return false
+            }
+        }
+
+        true
+    }
+
+    fn exe(&self, pid: u32) -> Option<PathBuf> {
+        let incarnation = Incarnation::of(pid)?;
+        let mut processes = self.processes.lock().unwrap();
+
+        if let Some(info) = processes.get(&pid) {
+            if info.incarnation == incarnation {
+                return info.exe.clone()
+            }
+        }
+
+        if processes.len() >= PROCESS_CACHE_LIMIT {
+            processes.clear();
+        }
+
+        let exe = fs::read_link(format!("/proc/{pid}/exe")).ok();
+        processes.insert(pid, ProcessInfo { incarnation, exe: exe.clone() });
+
+        exe
+    }
+}
+
+// processes move between cgroups freely, so this is resolved per request and never cached
+fn cgroup_of(pid: u32) -> Option<String> {
+    let cgroups = fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+
+    // the unified (v2) hierarchy is the line with id 0 and no controllers: `0::/path`
+    cgroups.lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| path.trim_end_matches(" (deleted)").to_owned())
+}
@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+use fuser::FileAttr;
+
+use super::ROOT_INO;
+use crate::fuse::inode::InodeTable;
+
+pub struct Directory {
+    pub attr: FileAttr,
+    pub parent: u64,
+    pub children: BTreeMap<OsString, u64>
+}
+
+/// Directories of the mount, mirroring the absolute paths of the patched files:
+/// the patch of `/etc/hosts` is served as `<mount point>/etc/hosts`.
+pub struct Tree {
+    template: FileAttr,
+    dirs: HashMap<u64, Directory>
+}
+
+impl Tree {
+    pub fn new(root: FileAttr) -> Self {
+        let dirs = HashMap::from([
+            (ROOT_INO, Directory { attr: root, parent: ROOT_INO, children: BTreeMap::new() })
+        ]);
+
+        Self { template: root, dirs }
+    }
+
+    /// Links `ino` at `path`, creating the missing parent directories.
+    /// Returns false if the path is taken, or one of its parents is a file.
+    pub fn insert(&mut self, path: &Path, ino: u64, inodes: &mut InodeTable) -> bool {
+        let components = components(path);
+
+        let Some((name, parents)) = components.split_last() else {
+            return false
+        };
+
+        let mut current = ROOT_INO;
+        let mut current_path = PathBuf::from("/");
+
+        for component in parents {
+            current_path.push(component);
+
+            let child = match self.lookup(current, component) {
+                Some(child) if self.dirs.contains_key(&child) => child,
+                Some(_) => return false,
+                None => {
+                    let child = inodes.allocate(&current_path);
+                    let attr = FileAttr { ino: child, ..self.template };
+
+                    self.dirs.insert(child, Directory { attr, parent: current, children: BTreeMap::new() });
+                    self.dirs.get_mut(&current).unwrap().children.insert(component.into(), child);
+
+                    child
+                }
+            };
+
+            current = child;
+        }
+
+        let children = &mut self.dirs.get_mut(&current).unwrap().children;
+
+        if children.contains_key(*name) {
+            return false
+        }
+
+        children.insert(name.into(), ino);
+
+        true
+    }
+
+    pub fn dir(&self, ino: u64) -> Option<&Directory> {
+        self.dirs.get(&ino)
+    }
+
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        self.dirs.get(&parent)?.children.get(name).copied()
+    }
+}
+
+pub fn components(path: &Path) -> Vec<&OsStr> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use fuser::FileType;
+
+    use super::*;
+
+    fn new() -> (Tree, InodeTable) {
+        let root = FileAttr {
+            ino: ROOT_INO,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+            flags: 0
+        };
+
+        (Tree::new(root), InodeTable::new())
+    }
+
+    fn insert(tree: &mut Tree, inodes: &mut InodeTable, path: &str) -> bool {
+        let ino = inodes.allocate(Path::new(path));
+        tree.insert(Path::new(path), ino, inodes)
+    }
+
+    #[test]
+    fn links_files_under_created_directories() {
+        let (mut tree, mut inodes) = new();
+
+        assert!(insert(&mut tree, &mut inodes, "/etc/hosts"));
+        assert!(insert(&mut tree, &mut inodes, "/etc/passwd"));
+
+        let etc = tree.lookup(ROOT_INO, OsStr::new("etc")).unwrap();
+        assert_eq!(tree.dir(etc).unwrap().parent, ROOT_INO);
+        assert_eq!(tree.lookup(etc, OsStr::new("hosts")), Some(inodes.allocate(Path::new("/etc/hosts"))));
+        assert_eq!(tree.dir(etc).unwrap().children.len(), 2);
+    }
+
+    #[test]
+    fn refuses_a_path_taken_twice() {
+        let (mut tree, mut inodes) = new();
+
+        assert!(insert(&mut tree, &mut inodes, "/etc/hosts"));
+        assert!(!insert(&mut tree, &mut inodes, "/etc/hosts"));
+    }
+
+    #[test]
+    fn refuses_files_and_directories_on_each_other() {
+        let (mut tree, mut inodes) = new();
+
+        assert!(insert(&mut tree, &mut inodes, "/etc/hosts"));
+        assert!(!insert(&mut tree, &mut inodes, "/etc/hosts/file"));
+        assert!(!insert(&mut tree, &mut inodes, "/etc"));
+    }
+
+    #[test]
+    fn refuses_the_root() {
+        let (mut tree, mut inodes) = new();
+        assert!(!insert(&mut tree, &mut inodes, "/"));
+    }
+}
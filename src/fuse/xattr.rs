@@ -0,0 +1,72 @@
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+
+use fuser::ReplyXattr;
+use libc::ERANGE;
+use rustix::fs as rfs;
+
+/// Reads an attribute of the original file, `Ok(None)` if it doesn't have one.
+pub fn original(path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+    loop {
+        let size = match rfs::getxattr(path, name, &mut []) {
+            Ok(size) => size,
+            Err(rustix::io::Errno::NODATA) => return Ok(None),
+            Err(err) => return Err(err.into())
+        };
+
+        let mut value = vec![0; size];
+
+        // the attribute may grow between the two calls, just retry
+        match rfs::getxattr(path, name, &mut value) {
+            Ok(size) => {
+                value.truncate(size);
+                return Ok(Some(value))
+            }
+            Err(rustix::io::Errno::RANGE) => continue,
+            Err(rustix::io::Errno::NODATA) => return Ok(None),
+            Err(err) => return Err(err.into())
+        }
+    }
+}
+
+/// Lists the attribute names of the original file.
+pub fn original_names(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    loop {
+        let size = rfs::listxattr(path, &mut [])?;
+        let mut list = vec![0; size];
+
+        match rfs::listxattr(path, &mut list) {
+            Ok(size) => {
+                let names = list[..size]
+                    .split(|c| *c == 0)
+                    .filter(|name| !name.is_empty())
+                    .map(<[u8]>::to_vec)
+                    .collect();
+
+                return Ok(names)
+            }
+            Err(rustix::io::Errno::RANGE) => continue,
+            Err(err) => return Err(err.into())
+        }
+    }
+}
+
+/// Joins names into the NUL-terminated list format of `listxattr(2)`.
+pub fn join_names<'a>(names: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    names.into_iter().fold(vec![], |mut list, name| {
+        list.extend(name);
+        list.push(0);
+        list
+    })
+}
+
+pub fn reply(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as _);
+    } else if value.len() > size as _ {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
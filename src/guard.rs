@@ -0,0 +1,47 @@
+use anyhow::Result;
+use libc::{SIG_IGN, SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use log::{debug, warn};
+use rustix::event::{poll, PollFd, PollFlags};
+use rustix::io::Errno;
+use rustix::process::{self, Pid, PidfdFlags};
+
+use crate::mount;
+
+/// Waits for the daemon to exit and removes the mounts it couldn't, e.g. when it was SIGKILLed.
+pub fn main(pid: i32) -> Result<()> {
+    // systemd (KillMode=control-group) signals every process of the unit at once, the daemon
+    // included. the guard has to outlive the daemon to clean up after it, so only the daemon's
+    // end, or its SIGKILL on a clean exit, stops it
+    for signal in [SIGTERM, SIGINT, SIGHUP, SIGQUIT] {
+        unsafe { libc::signal(signal, SIG_IGN) };
+    }
+
+    let pid = Pid::from_raw(pid).unwrap();
+    let pidfd = process::pidfd_open(pid, PidfdFlags::empty())?;
+
+    // the daemon may have died before we got the pidfd, and the pid been reused since
+    if process::getppid() == Some(pid) {
+        let mut fds = [PollFd::new(&pidfd, PollFlags::IN)];
+
+        loop {
+            match poll(&mut fds, -1) {
+                Err(Errno::INTR) => continue,
+                result => {
+                    result?;
+                    break;
+                }
+            }
+        }
+    }
+
+    // a clean exit kills us before we get here, anything mounted now is left over
+    let removed = mount::cleanup()?;
+
+    if removed > 0 {
+        warn!("daemon {} died, removed {removed} leftover mounts", pid.as_raw_nonzero());
+    } else {
+        debug!("daemon {} exited", pid.as_raw_nonzero());
+    }
+
+    Ok(())
+}
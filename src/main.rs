@@ -15,9 +15,10 @@ mod configs;
 mod dirs;
 mod mount;
 mod cli;
-mod hash;
+mod ctl;
 mod daemon;
 mod extensions;
+mod guard;
 mod pipeback;
 
 fn check_permissions() -> Result<()> {
@@ -56,13 +57,19 @@ fn main() -> Result<()> {
             let runtime = Runtime::new()?;
             runtime.block_on(daemon::main())?;
         }
-        Some(Operation::MountFuse) => {
+        Some(Operation::MountFuse(args)) => {
             mount::unshare()?;
-            fuse::mount(configs::parse())?;
+            fuse::mount(args.generation)?;
         },
         Some(Operation::PipeBack(args)) => {
             pipeback::main(args.pid)?;
         }
+        Some(Operation::Guard(args)) => {
+            guard::main(args.pid)?;
+        }
+        Some(Operation::Ctl(args)) => {
+            ctl::main(args.request)?;
+        }
     }
 
     Ok(())
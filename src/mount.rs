@@ -1,10 +1,11 @@
-use std::fs;
+use std::fs::{self, File};
 use std::os::fd::{AsFd, OwnedFd};
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
 use log::debug;
-use rustix::{mount, process, thread};
+use rustix::{event, io, mount, process, thread};
+use rustix::event::{PollFd, PollFlags};
 use rustix::fs::{CWD, Mode, OFlags};
 use rustix::fs as rfs;
 use rustix::mount::{MountPropagationFlags, MoveMountFlags, OpenTreeFlags, UnmountFlags};
@@ -33,8 +34,10 @@ pub fn bind_mount(source: &PathBuf, target: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-pub fn cleanup() -> Result<()> {
+/// Unmounts the fuse mount and every bind of it, returns how many mounts were removed.
+pub fn cleanup() -> Result<usize> {
     let mounts = fs::read_to_string("/proc/self/mounts")?;
+    let mut removed = 0;
 
     for line in mounts.split('\n') {
         if line.is_empty() {
@@ -49,9 +52,33 @@ pub fn cleanup() -> Result<()> {
         if fs == env!("CARGO_CRATE_NAME") {
             mount::unmount(mp, UnmountFlags::DETACH)?;
             debug!("unmount: {}", mp);
+
+            removed += 1;
         }
     }
 
+    Ok(removed)
+}
+
+/// Calls `changed` on every change to the mount table of this namespace, until it returns false.
+pub fn watch(changed: impl Fn() -> bool + Send + 'static) -> Result<()> {
+    let mountinfo = File::open("/proc/self/mountinfo")?;
+
+    std::thread::Builder::new()
+        .name("mountinfo".to_owned())
+        .spawn(move || {
+            // the kernel flags POLLPRI once per change and resets it on the next poll
+            let mut fds = [PollFd::new(&mountinfo, PollFlags::PRI)];
+
+            loop {
+                match event::poll(&mut fds, -1) {
+                    Ok(_) if changed() => (),
+                    Err(io::Errno::INTR) => (),
+                    _ => break
+                }
+            }
+        })?;
+
     Ok(())
 }
 